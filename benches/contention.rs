@@ -0,0 +1,49 @@
+//! Compares the mutex collector against the sharded collector when many threads increment counters.
+//!
+//! Run with `cargo bench --bench contention`, on a machine with a core per thread: the sharded
+//! collector only helps when threads run in parallel, and otherwise pays for buffering and replaying
+//! without anything to gain.
+use debug_metrics::{DebugMetrics, DebugMetricsConfig, DebugMetricsSafeTrait, NoLabels};
+use std::io::sink;
+use std::time::{Duration, Instant};
+
+const THREAD_COUNTS: &[usize] = &[1, 4, 16, 32];
+const INCS_PER_THREAD: usize = 10_000;
+
+/// Time how long `threads` threads take to increment a counter each, excluding the final merge.
+fn bench<DM: DebugMetricsSafeTrait + Send + 'static>(
+    debug_metrics: DM,
+    threads: usize,
+) -> Duration {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let debug_metrics = debug_metrics.clone();
+            std::thread::spawn(move || {
+                let key = format!("worker_{t}");
+                for _ in 0..INCS_PER_THREAD {
+                    debug_metrics.inc(key.as_str(), NoLabels);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    println!("{INCS_PER_THREAD} incs per thread, default config");
+    for &threads in THREAD_COUNTS {
+        let config = DebugMetricsConfig::default();
+        let mutex = bench(DebugMetrics::new(sink(), config).safe(), threads);
+        let sharded = bench(DebugMetrics::new(sink(), config).sharded(), threads);
+        println!(
+            "{threads:>3} threads: mutex {:>10.1?}, sharded {:>10.1?}, {:.1}x faster",
+            mutex,
+            sharded,
+            mutex.as_secs_f64() / sharded.as_secs_f64()
+        );
+    }
+}
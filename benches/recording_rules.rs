@@ -0,0 +1,96 @@
+//! Compares recording rule evaluation against the previous approach of compiling every pattern for
+//! every key on every event.
+//!
+//! Run with `cargo bench --bench recording_rules`.
+use debug_metrics::{DebugMetrics, DebugMetricsConfig, DebugMetricsTrait, KeyPattern, NoLabels};
+use std::collections::BTreeMap;
+use std::hint::black_box;
+use std::io::sink;
+use std::time::{Duration, Instant};
+
+const KEY_COUNTS: &[usize] = &[1_000, 5_000, 10_000];
+const EVENTS: usize = 20;
+const PATTERNS: &[&str] = &["^db\\.", "^cache\\.hits$"];
+
+/// The matching that used to run on every event.
+fn naive_matching(patterns: &[&str], counts: &BTreeMap<String, u64>) -> BTreeMap<String, u64> {
+    let mut ret = BTreeMap::new();
+    for patt in patterns {
+        for (k, v) in counts {
+            let re = regex::Regex::new(patt).unwrap();
+            if re.is_match(k) {
+                ret.insert(k.clone(), *v);
+            }
+        }
+    }
+    ret
+}
+
+fn keys(n: usize) -> impl Iterator<Item = String> {
+    (0..n).map(|i| match i % 10 {
+        0 => format!("db.table_{i}"),
+        _ => format!("service.key_{i}"),
+    })
+}
+
+fn bench_naive(n: usize) -> Duration {
+    let mut counts = BTreeMap::new();
+    for key in keys(n) {
+        counts.insert(key, 1);
+    }
+    let start = Instant::now();
+    for _ in 0..EVENTS {
+        *counts.entry("query".to_string()).or_default() += 1;
+        black_box(naive_matching(PATTERNS, &counts));
+    }
+    start.elapsed()
+}
+
+fn bench_debug_metrics(n: usize) -> Duration {
+    let mut debug_metrics = DebugMetrics::new(sink(), DebugMetricsConfig::default());
+    for key in keys(n) {
+        debug_metrics.inc(key, NoLabels);
+    }
+    debug_metrics.add_recording_rule("query", PATTERNS);
+    let start = Instant::now();
+    for _ in 0..EVENTS {
+        debug_metrics.inc("query", NoLabels);
+    }
+    let elapsed = start.elapsed();
+    black_box(debug_metrics.events_for_key("query"));
+    elapsed
+}
+
+/// The same rule, triggered by a glob instead of the exact key.
+fn bench_pattern_trigger(n: usize) -> Duration {
+    let mut debug_metrics = DebugMetrics::new(sink(), DebugMetricsConfig::default());
+    for key in keys(n) {
+        debug_metrics.inc(key, NoLabels);
+    }
+    debug_metrics.add_pattern_recording_rule(KeyPattern::Glob("quer*"), PATTERNS);
+    let start = Instant::now();
+    for _ in 0..EVENTS {
+        debug_metrics.inc("query", NoLabels);
+    }
+    let elapsed = start.elapsed();
+    black_box(debug_metrics.events_for_key("query"));
+    elapsed
+}
+
+fn main() {
+    println!("{EVENTS} events per run, patterns {PATTERNS:?}");
+    for &n in KEY_COUNTS {
+        let naive = bench_naive(n);
+        let compiled = bench_debug_metrics(n);
+        println!(
+            "{n:>6} keys: per-event regex {:>10.1?}/event, compiled rules {:>10.1?}/event, {:.0}x faster",
+            naive / EVENTS as u32,
+            compiled / EVENTS as u32,
+            naive.as_secs_f64() / compiled.as_secs_f64()
+        );
+        println!(
+            "{n:>6} keys: pattern triggered rules {:>10.1?}/event",
+            bench_pattern_trigger(n) / EVENTS as u32
+        );
+    }
+}
@@ -0,0 +1,15 @@
+//! Decides whether metrics are collected, as `cfg(debug_metrics_enabled)`.
+//!
+//! Metrics follow `debug_assertions`, unless overridden by the `force-enable` or `force-disable`
+//! features. When both features are set, `force-disable` wins.
+use std::env;
+
+fn main() {
+    println!("cargo::rustc-check-cfg=cfg(debug_metrics_enabled)");
+    let force_enable = env::var_os("CARGO_FEATURE_FORCE_ENABLE").is_some();
+    let force_disable = env::var_os("CARGO_FEATURE_FORCE_DISABLE").is_some();
+    let debug_assertions = env::var_os("CARGO_CFG_DEBUG_ASSERTIONS").is_some();
+    if !force_disable && (force_enable || debug_assertions) {
+        println!("cargo::rustc-cfg=debug_metrics_enabled");
+    }
+}
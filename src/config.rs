@@ -2,20 +2,142 @@ use crate::debug_metrics::DefaultExt;
 
 #[derive(Clone, Copy)]
 pub struct DebugMetricsConfig {
+    /// Runtime switch for collection. Metrics are only collected when this is true and collection
+    /// is compiled in, which it is with debug assertions or the `force-enable` feature, unless the
+    /// `force-disable` feature is set.
+    pub enabled: bool,
     /// When true, events will always be recorded and printed, even if there is no rule
     pub process_all_events: bool,
     /// Record label change events
     pub record_label_changes: bool,
     /// Include all labels for every event
     pub all_labels_every_event: bool,
+    /// Prefix every event in the report with its sequence number and elapsed time.
+    ///
+    /// Enabled by [DefaultExt::default_on]. Times make the report differ between runs, unless the
+    /// collector is given a [crate::ManualClock].
+    pub report_event_times: bool,
+    /// Prefix every event in the report with the `file:line:column` of the call that caused it, and
+    /// finish the report with how many times each call site touched each key.
+    ///
+    /// Enabled by [DefaultExt::default_on]. Locations change with every edit of the caller, so turn
+    /// this off to compare reports across edits.
+    pub report_locations: bool,
+    /// Report the events of each thread together, in the order their threads first recorded an
+    /// event, and prefix every event with the name or id of its thread. Streamed events are
+    /// written as they happen, so they are only prefixed.
+    pub group_by_thread: bool,
+    /// Which of the built in reporters writes the report, see [crate::DebugMetrics::with_reporter]
+    /// for others
+    pub output_format: OutputFormat,
+    /// When the events are written
+    pub output_mode: OutputMode,
+    /// Which events are kept in memory
+    pub retention: RetentionPolicy,
+    /// The buckets of the histograms of [crate::DebugMetricsTrait::record]
+    pub histogram_buckets: HistogramBuckets,
+    /// Also count every combination of a metric and the labels passed with it as a separate
+    /// series, see [crate::DebugMetricsTrait::series]. The count of the metric itself is still
+    /// kept for rules and dependencies.
+    pub series: bool,
+    /// Labels to sum the series by in the report, like `sum by (...)` in PromQL. When empty,
+    /// every series is reported.
+    pub series_sum_by: &'static [&'static str],
+}
+
+/// Format of the report written on drop or [crate::DebugMetrics::finish].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// [crate::TextReporter]
+    #[default]
+    Text,
+    /// [crate::JsonLinesReporter]
+    JsonLines,
+    /// [crate::ChromeTraceReporter]
+    ChromeTrace,
+    /// [crate::PrometheusReporter]
+    Prometheus,
+    /// [crate::DotReporter]
+    Dot,
+}
+
+/// When events are written to the output writer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Buffer all events and write them on drop or [crate::DebugMetrics::finish]
+    #[default]
+    OnDrop,
+    /// Write every event as soon as it is recorded, so that output survives a hang, a kill or a
+    /// leaked collector. Whether an event is printed is decided when it is recorded, so drop hooks
+    /// need to be added before the events they should print.
+    ///
+    /// Events are still kept for [crate::DebugMetricsTrait::events_for_key].
+    Streaming(FlushPolicy),
+}
+
+/// How often a streaming writer is flushed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    EveryEvent,
+    EveryNEvents(usize),
+    /// Flush on the first event written after the interval has passed since the last flush,
+    /// measured by the [crate::Clock] of the collector.
+    ///
+    /// Nothing is flushed while no events are written, so events written just before a hang can
+    /// stay in a buffered writer. Use [crate::DebugMetricsSafe::spawn_stream_flusher] to flush
+    /// them from a background thread as well.
+    Interval(std::time::Duration),
+}
+
+/// Which events are kept in memory, for the report and [crate::DebugMetricsTrait::events_for_key].
+///
+/// Events that are not kept are counted and the count is included in the report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RetentionPolicy {
+    #[default]
+    Unbounded,
+    /// Keep the most recent events, like a flight recorder
+    KeepLast(usize),
+    /// Keep the earliest events and drop the rest
+    KeepFirst(usize),
+    /// Keep the most recent events that fit in roughly this many bytes, see
+    /// [crate::Event::approximate_size]
+    ByteBudget(usize),
+}
+
+/// How the values of a [crate::Histogram] are bucketed, which limits the precision of percentiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramBuckets {
+    /// Like HDR histograms: every power of two is split into `2^sub_bucket_bits` equal buckets, so
+    /// percentiles are within `1 / 2^sub_bucket_bits` of the recorded values, whatever their scale
+    LogLinear { sub_bucket_bits: u8 },
+    /// Inclusive upper bounds, in increasing order. Values above the last bound are bucketed
+    /// together.
+    Explicit(&'static [u64]),
+}
+
+impl Default for HistogramBuckets {
+    fn default() -> Self {
+        HistogramBuckets::LogLinear { sub_bucket_bits: 4 }
+    }
 }
 
 impl Default for DebugMetricsConfig {
     fn default() -> Self {
         DebugMetricsConfig {
+            enabled: true,
             process_all_events: false,
             record_label_changes: false,
             all_labels_every_event: false,
+            report_event_times: false,
+            report_locations: false,
+            group_by_thread: false,
+            output_format: OutputFormat::Text,
+            output_mode: OutputMode::OnDrop,
+            retention: RetentionPolicy::Unbounded,
+            histogram_buckets: HistogramBuckets::default(),
+            series: false,
+            series_sum_by: &[],
         }
     }
 }
@@ -23,9 +145,19 @@ impl Default for DebugMetricsConfig {
 impl DefaultExt for DebugMetricsConfig {
     fn default_on() -> Self {
         DebugMetricsConfig {
+            enabled: true,
             process_all_events: true,
             record_label_changes: true,
             all_labels_every_event: true,
+            report_event_times: true,
+            report_locations: true,
+            group_by_thread: false,
+            output_format: OutputFormat::Text,
+            output_mode: OutputMode::OnDrop,
+            retention: RetentionPolicy::Unbounded,
+            histogram_buckets: HistogramBuckets::default(),
+            series: false,
+            series_sum_by: &[],
         }
     }
 }
@@ -1,9 +1,41 @@
-use crate::config::DebugMetricsConfig;
+use crate::clock::{Clock, MonotonicClock};
+use crate::config::{DebugMetricsConfig, OutputFormat, OutputMode};
+#[cfg(debug_metrics_enabled)]
+use crate::config::{FlushPolicy, RetentionPolicy};
+use crate::debug_metrics_sharded::DebugMetricsSharded;
+#[cfg(debug_metrics_enabled)]
+use crate::debug_metrics_sharded::{BufferedOp, OpKind};
 use crate::drop_hook::DropHook;
+use crate::error::DebugMetricsError;
+use crate::histogram::Histogram;
+use crate::label_guard::LabelGuard;
 use crate::label_iter::LabelIter;
+use crate::prometheus::{write_file_atomically, PrometheusReporter};
+use crate::reporter::{
+    ChromeTraceReporter, DotReporter, JsonLinesReporter, Reporter, Summary, TextReporter,
+};
+use crate::rule::RecordingRuleBuilder;
+#[cfg(debug_metrics_enabled)]
+use crate::rule::{Comparison, Condition, RuleTrigger};
+#[cfg(debug_metrics_enabled)]
+use crate::series::LabelSet;
+use crate::series::{sum_by, Series};
+use crate::span::{self_time_by_stack, Span, SpanRecord, StackWeight};
+#[cfg(debug_metrics_enabled)]
+use crate::thread_exit::{notify_on_exit, ExitedThreads};
+use crate::timer::Timer;
 use crate::DebugMetricsSafe;
-use std::collections::{BTreeMap, BTreeSet};
+#[cfg(debug_metrics_enabled)]
+use regex::{Regex, RegexSet};
+#[cfg(debug_metrics_enabled)]
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::io::{stdout, Stdout, Write};
+use std::panic::Location;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::ThreadId;
+use std::time::Duration;
 
 /// DebugMetrics that serve as a convenient way to debug complex code.
 ///
@@ -11,46 +43,435 @@ use std::io::{stdout, Stdout, Write};
 pub struct DebugMetrics<W: Write> {
     /// Which other metrics need to be taken
     /// Regexes to match against keys.
-    rules: BTreeMap<String, BTreeSet<&'static str>>,
-    counts: BTreeMap<String, u64>,
+    #[cfg(debug_metrics_enabled)]
+    rules: BTreeMap<String, RecordingRule>,
+    /// Rules with conditions, see [DebugMetricsTrait::add_rule], which are kept apart from `rules`
+    /// so that each keeps its own conditions
+    #[cfg(debug_metrics_enabled)]
+    conditional_rules: BTreeMap<String, Vec<RecordingRule>>,
+    /// Rules triggered by every key that matches a pattern, see
+    /// [DebugMetricsTrait::add_pattern_recording_rule]
+    #[cfg(debug_metrics_enabled)]
+    pattern_rules: Vec<PatternRule>,
+    /// The triggers of `pattern_rules`, in the same order
+    #[cfg(debug_metrics_enabled)]
+    triggers: RegexSet,
+    /// Indices of the `pattern_rules` each key that was seen triggers
+    #[cfg(debug_metrics_enabled)]
+    triggered_by: HashMap<String, Vec<usize>>,
+    /// The value of the last event recorded for each key, for [Condition::Changed]. Only kept once
+    /// a rule needs it.
+    #[cfg(debug_metrics_enabled)]
+    last_event_values: Option<HashMap<String, Value>>,
+    counts: BTreeMap<String, i64>,
+    /// Keys in `counts` that were set with [DebugMetricsTrait::set_gauge], and so may go negative
+    #[cfg(debug_metrics_enabled)]
+    gauges: BTreeSet<String>,
+    /// Values of [DebugMetricsTrait::record]
+    histograms: BTreeMap<String, Histogram>,
+    /// Counts per label set, for [DebugMetricsConfig::series]
+    series: BTreeMap<String, Series>,
     labels: BTreeMap<String, String>,
-    events: Vec<EventType>,
+    /// Labels set with [DebugMetricsTrait::set_thread_label], which take precedence over `labels`
+    /// in the events of their thread
+    thread_labels: HashMap<ThreadId, BTreeMap<String, String>>,
+    /// Threads with `thread_labels` that have exited, whose labels are yet to be removed
+    #[cfg(debug_metrics_enabled)]
+    exited_threads: ExitedThreads,
+    /// Events kept according to [DebugMetricsConfig::retention]
+    events: VecDeque<Event>,
+    /// Events that were recorded but not kept
+    evicted_events: u64,
+    /// Approximate size of `events`, for [RetentionPolicy::ByteBudget]
+    #[cfg(debug_metrics_enabled)]
+    retained_bytes: usize,
+    #[cfg(debug_metrics_enabled)]
+    next_sequence: u64,
+    /// How many times each call site touched each key
+    call_sites: CallSites,
+    /// The spans that are open on each thread, outermost first
+    #[cfg(debug_metrics_enabled)]
+    open_spans: HashMap<ThreadId, Vec<OpenSpan>>,
+    closed_spans: Vec<SpanRecord>,
+    /// Events recorded per stack of spans, including events that were not kept
+    events_by_stack: BTreeMap<String, u64>,
+    clock: Arc<dyn Clock>,
+    /// When and on which thread the operation being replayed by [DebugMetricsSharded] happened
+    #[cfg(debug_metrics_enabled)]
+    replaying: Option<(Duration, ThreadInfo)>,
+    reporter: Box<dyn Reporter>,
+    /// Progress of [OutputMode::Streaming]
+    stream: StreamState,
     drop_print: BTreeSet<String>,
-    output_writer: W,
+    /// Only taken by [DebugMetrics::finish] or on drop, and while writing to it
+    output_writer: Option<W>,
+    /// Whether the report was written, by [DebugMetricsTrait::flush_report], [DebugMetrics::finish]
+    /// or on drop, so that it is only written once
+    report_written: bool,
     config: DebugMetricsConfig,
 }
 
+/// A recording rule with its patterns compiled once, at registration.
+///
+/// Keys are matched against the rule the first time they are seen, so events only need to look up
+/// the values of keys that are already known to match.
+#[cfg(debug_metrics_enabled)]
+struct RecordingRule {
+    patterns: Vec<&'static str>,
+    regex_set: RegexSet,
+    /// Keys in `counts` or `labels` that match at least one of the patterns.
+    matched_keys: BTreeSet<String>,
+    /// All of which need to hold for an event to be recorded
+    conditions: Vec<RuleCondition>,
+}
+
+/// A [Condition], with its regex compiled once, at registration.
+#[cfg(debug_metrics_enabled)]
+enum RuleCondition {
+    Compare(Comparison, i64),
+    LabelMatches(Regex),
+    Changed,
+}
+
+#[cfg(debug_metrics_enabled)]
+impl RuleCondition {
+    fn new(metric: &str, condition: Condition) -> Result<Self, DebugMetricsError> {
+        Ok(match condition {
+            Condition::Compare(comparison, constant) => {
+                RuleCondition::Compare(comparison, constant)
+            }
+            Condition::LabelMatches(pattern) => {
+                let regex = Regex::new(pattern).map_err(|source| {
+                    DebugMetricsError::InvalidRecordingRule {
+                        metric: metric.to_string(),
+                        pattern,
+                        source,
+                    }
+                })?;
+                RuleCondition::LabelMatches(regex)
+            }
+            Condition::Changed => RuleCondition::Changed,
+        })
+    }
+
+    fn holds(&self, value: &Value, last: Option<&Value>) -> bool {
+        match (self, value) {
+            (RuleCondition::Compare(comparison, constant), Value::Metric(value)) => {
+                comparison.holds(*value, *constant)
+            }
+            (RuleCondition::LabelMatches(regex), Value::Label(value)) => regex.is_match(value),
+            (RuleCondition::Changed, value) => last != Some(value),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(debug_metrics_enabled)]
+impl RecordingRule {
+    fn new() -> Self {
+        RecordingRule {
+            patterns: Vec::new(),
+            regex_set: RegexSet::empty(),
+            matched_keys: BTreeSet::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Add patterns and recompile the set. Returns true if the set changed.
+    ///
+    /// Patterns are validated before anything is added, so an invalid pattern leaves the rule as is.
+    fn extend(
+        &mut self,
+        metric: &str,
+        additional: &[&'static str],
+    ) -> Result<bool, DebugMetricsError> {
+        let mut patterns = self.patterns.clone();
+        for patt in additional {
+            if patterns.contains(patt) {
+                continue;
+            }
+            if let Err(source) = regex::Regex::new(patt) {
+                return Err(DebugMetricsError::InvalidRecordingRule {
+                    metric: metric.to_string(),
+                    pattern: patt,
+                    source,
+                });
+            }
+            patterns.push(patt);
+        }
+        if patterns.len() == self.patterns.len() {
+            return Ok(false);
+        }
+        self.regex_set =
+            RegexSet::new(&patterns).map_err(|source| DebugMetricsError::InvalidRecordingRule {
+                metric: metric.to_string(),
+                pattern: patterns.last().unwrap(),
+                source,
+            })?;
+        self.patterns = patterns;
+        Ok(true)
+    }
+
+    fn index_key(&mut self, key: &str) {
+        if self.regex_set.is_match(key) {
+            self.matched_keys.insert(key.to_string());
+        }
+    }
+}
+
+/// A recording rule whose trigger is a pattern rather than a key.
+#[cfg(debug_metrics_enabled)]
+struct PatternRule {
+    trigger: KeyPattern,
+    rule: RecordingRule,
+}
+
+/// The keys that trigger a recording rule added with
+/// [DebugMetricsTrait::add_pattern_recording_rule].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyPattern {
+    /// Keys the regex matches anywhere in, like the captured side of a rule, e.g. `^db\.`
+    Regex(&'static str),
+    /// Whole keys, where `*` matches any characters and `?` matches one, e.g. `db.*`
+    Glob(&'static str),
+}
+
+#[cfg(debug_metrics_enabled)]
+impl KeyPattern {
+    fn pattern(&self) -> &'static str {
+        match self {
+            KeyPattern::Regex(pattern) | KeyPattern::Glob(pattern) => pattern,
+        }
+    }
+
+    fn to_regex(self) -> String {
+        match self {
+            KeyPattern::Regex(pattern) => pattern.to_string(),
+            KeyPattern::Glob(pattern) => {
+                let mut regex = String::from("^");
+                for c in pattern.chars() {
+                    match c {
+                        '*' => regex.push_str(".*"),
+                        '?' => regex.push('.'),
+                        _ => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                    }
+                }
+                regex.push('$');
+                regex
+            }
+        }
+    }
+}
+
+#[cfg(debug_metrics_enabled)]
+struct OpenSpan {
+    /// See [SpanRecord::stack]
+    stack: String,
+    start: Duration,
+}
+
+#[derive(Default)]
+struct StreamState {
+    #[cfg(debug_metrics_enabled)]
+    unflushed_events: usize,
+    #[cfg(debug_metrics_enabled)]
+    last_flush: Duration,
+    /// The first write failure, after which nothing more is streamed
+    error: Option<std::io::Error>,
+}
+
+/// Where [DebugMetricsTrait::dump] writes to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpTarget {
+    /// The writer the report is written to
+    Writer,
+    Stderr,
+}
+
+/// A recorded [EventType], with when it happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    /// Starts at 0 and increases by one for every recorded event
+    pub sequence: u64,
+    /// Time since the [DebugMetrics] was created, according to its [Clock]
+    pub elapsed: Duration,
+    /// The `inc`, `set` or `set_label` call that caused the event
+    pub location: &'static Location<'static>,
+    /// The innermost span that was open on the thread that recorded the event, as the stack of
+    /// span names described in [SpanRecord::stack]
+    pub span: Option<String>,
+    /// The thread that recorded the event
+    pub thread: ThreadId,
+    /// The name of the thread that recorded the event, if it was named
+    pub thread_name: Option<String>,
+    pub event_type: EventType,
+}
+
+impl Event {
+    /// Rough number of bytes the event takes up in memory, for [RetentionPolicy::ByteBudget].
+    pub fn approximate_size(&self) -> usize {
+        let no_dependencies = BTreeMap::new();
+        let (cause, value, dependencies, labels) = match &self.event_type {
+            EventType::MetricChange {
+                dependencies,
+                labels,
+                ..
+            } => (None, None, dependencies, labels),
+            EventType::LabelChange {
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (None, Some(value), dependencies, labels),
+            EventType::CascadeMetricChange {
+                cause,
+                dependencies,
+                labels,
+                ..
+            } => (Some(cause), None, dependencies, labels),
+            EventType::CascadeLabelChange {
+                cause,
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (Some(cause), Some(value), dependencies, labels),
+            EventType::LabelRemoved {
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (None, Some(value), dependencies, labels),
+            EventType::Sample {
+                dependencies,
+                labels,
+                ..
+            }
+            | EventType::Timing {
+                dependencies,
+                labels,
+                ..
+            } => (None, None, dependencies, labels),
+            EventType::Underflow { labels, .. } => (None, None, &no_dependencies, labels),
+        };
+        size_of::<Event>()
+            + self.event_type.key().len()
+            + cause.map_or(0, String::len)
+            + value.map_or(0, String::len)
+            + self.span.as_ref().map_or(0, String::len)
+            + self.thread_name.as_ref().map_or(0, String::len)
+            + dependencies
+                .keys()
+                .map(|k| k.len() + size_of::<i64>())
+                .sum::<usize>()
+            + labels.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+    }
+}
+
+/// The thread an operation happened on, as [DebugMetricsSharded] buffers it.
+#[cfg(debug_metrics_enabled)]
+#[derive(Clone)]
+pub(crate) struct ThreadInfo {
+    pub(crate) id: ThreadId,
+    pub(crate) name: Option<Arc<str>>,
+}
+
+#[cfg(debug_metrics_enabled)]
+impl ThreadInfo {
+    pub(crate) fn current() -> Self {
+        let thread = std::thread::current();
+        ThreadInfo {
+            id: thread.id(),
+            name: thread.name().map(Arc::from),
+        }
+    }
+}
+
+/// Per call site, how many each key was touched from there.
+pub type CallSites = BTreeMap<&'static Location<'static>, BTreeMap<String, u64>>;
+
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum EventType {
     MetricChange {
         metric: String,
-        count: u64,
-        dependencies: BTreeMap<String, u64>,
+        count: i64,
+        dependencies: BTreeMap<String, i64>,
         labels: BTreeMap<String, String>,
     },
     LabelChange {
         label: String,
         value: String,
-        dependencies: BTreeMap<String, u64>,
+        dependencies: BTreeMap<String, i64>,
         labels: BTreeMap<String, String>,
     },
     CascadeMetricChange {
         cause: String,
         metric: String,
-        count: u64,
-        dependencies: BTreeMap<String, u64>,
+        count: i64,
+        dependencies: BTreeMap<String, i64>,
         labels: BTreeMap<String, String>,
     },
     CascadeLabelChange {
         cause: String,
         label: String,
         value: String,
-        dependencies: BTreeMap<String, u64>,
+        dependencies: BTreeMap<String, i64>,
+        labels: BTreeMap<String, String>,
+    },
+    /// A label was removed, see [DebugMetricsTrait::remove_label].
+    LabelRemoved {
+        label: String,
+        /// The value the label had
+        value: String,
+        dependencies: BTreeMap<String, i64>,
+        labels: BTreeMap<String, String>,
+    },
+    /// A value was added to the histogram of a metric, see [DebugMetricsTrait::record].
+    Sample {
+        metric: String,
+        value: u64,
+        dependencies: BTreeMap<String, i64>,
+        labels: BTreeMap<String, String>,
+    },
+    /// A [crate::Timer] finished, and its duration was added to the histogram of a metric in
+    /// nanoseconds.
+    Timing {
+        metric: String,
+        duration: Duration,
+        /// The timer was dropped while unwinding from a panic
+        panicked: bool,
+        dependencies: BTreeMap<String, i64>,
+        labels: BTreeMap<String, String>,
+    },
+    /// A counter would have gone below zero, and was set to zero instead.
+    ///
+    /// Always recorded and printed, with all labels at the time, or with the labels of the series
+    /// when a series of [DebugMetricsConfig::series] would have gone below zero.
+    Underflow {
+        metric: String,
+        /// The count before the subtraction
+        count: i64,
+        /// What was subtracted
+        amount: u64,
         labels: BTreeMap<String, String>,
     },
 }
 
 impl EventType {
+    /// The metric or label that changed.
+    pub fn key(&self) -> &str {
+        match self {
+            EventType::MetricChange { metric, .. } => metric,
+            EventType::LabelChange { label, .. } => label,
+            EventType::CascadeMetricChange { metric, .. } => metric,
+            EventType::CascadeLabelChange { label, .. } => label,
+            EventType::LabelRemoved { label, .. } => label,
+            EventType::Sample { metric, .. } => metric,
+            EventType::Timing { metric, .. } => metric,
+            EventType::Underflow { metric, .. } => metric,
+        }
+    }
+
     pub fn promote_to_cascade(self, cause: &str) -> Self {
         match self {
             EventType::MetricChange {
@@ -102,25 +523,282 @@ impl DefaultExt for DebugMetrics<Stdout> {
     }
 }
 
+/// How [DebugMetrics::update_metric] changes a count.
+#[cfg(debug_metrics_enabled)]
+#[derive(Clone, Copy)]
+pub(crate) enum MetricUpdate {
+    Add(u64),
+    Sub(u64),
+    Set(u64),
+    SetGauge(i64),
+}
+
+/// What [DebugMetrics::record_sample] adds to a histogram.
+#[cfg(debug_metrics_enabled)]
+pub(crate) enum SampleValue {
+    Value(u64),
+    /// Recorded in nanoseconds
+    Duration {
+        duration: Duration,
+        panicked: bool,
+    },
+}
+
+/// The value of a key, or of a sample, that a [RuleCondition] is checked against.
+#[cfg(debug_metrics_enabled)]
+#[derive(Clone, PartialEq)]
 enum Value {
-    Metric(u64),
+    Metric(i64),
     Label(String),
 }
 
 pub trait DebugMetricsTrait {
-    fn add_recording_rule<Key: Into<String>>(&mut self, metric: Key, additional: &[&'static str]);
+    /// Include regex recording rules.
+    ///
+    /// Panics if any of the patterns is not a valid regex.
+    #[track_caller]
+    fn add_recording_rule<Key: Into<String>>(&mut self, metric: Key, additional: &[&'static str]) {
+        if let Err(e) = self.try_add_recording_rule(metric, additional) {
+            panic!("{e}");
+        }
+    }
+
+    /// Include regex recording rules, returning an error if any of the patterns is invalid.
+    fn try_add_recording_rule<Key: Into<String>>(
+        &mut self,
+        metric: Key,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError>;
+
+    /// Include regex recording rules for every key that matches `trigger`, e.g. to capture a
+    /// `request_id` label with every `db.*` metric.
+    ///
+    /// A key that triggers several rules, pattern or exact, captures what all of them capture.
+    /// Panics if the trigger or any of the patterns is not a valid regex.
+    #[track_caller]
+    fn add_pattern_recording_rule(&mut self, trigger: KeyPattern, additional: &[&'static str]) {
+        if let Err(e) = self.try_add_pattern_recording_rule(trigger, additional) {
+            panic!("{e}");
+        }
+    }
+
+    /// Include regex recording rules for every key that matches `trigger`, returning an error if
+    /// the trigger or any of the patterns is invalid.
+    fn try_add_pattern_recording_rule(
+        &mut self,
+        trigger: KeyPattern,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError>;
+
+    /// Include a recording rule built with a [RecordingRuleBuilder], e.g. one that only records
+    /// an event when a retry counter goes above 3.
+    ///
+    /// Conditions only decide whether the rule records an event: with
+    /// [DebugMetricsConfig::process_all_events] every change is still recorded. Panics if any of the
+    /// patterns is not a valid regex.
+    #[track_caller]
+    fn add_rule(&mut self, rule: RecordingRuleBuilder) {
+        if let Err(e) = self.try_add_rule(rule) {
+            panic!("{e}");
+        }
+    }
+
+    /// Include a recording rule built with a [RecordingRuleBuilder], returning an error if any of
+    /// the patterns is invalid.
+    fn try_add_rule(&mut self, rule: RecordingRuleBuilder) -> Result<(), DebugMetricsError>;
 
     fn add_drop_hook<Key: Into<String>>(&mut self, key: Key);
 
+    #[track_caller]
     fn inc<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, labels: Iter);
 
+    /// Decrement a counter by one, see [DebugMetricsTrait::sub].
+    #[track_caller]
+    fn dec<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, labels: Iter);
+
+    #[track_caller]
+    fn add<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, n: u64, labels: Iter);
+
+    /// Subtract from a counter.
+    ///
+    /// Counters do not go below zero: instead they stay at zero and an [EventType::Underflow] is
+    /// recorded. Gauges, see [DebugMetricsTrait::set_gauge], go negative.
+    #[track_caller]
+    fn sub<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, n: u64, labels: Iter);
+
+    #[track_caller]
     fn set<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: u64, labels: Iter);
 
+    /// Set a signed value, and treat the key as a gauge that may go negative from then on.
+    #[track_caller]
+    fn set_gauge<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: i64, labels: Iter);
+
+    /// Add a value to the histogram of a metric, e.g. a batch size or a latency.
+    ///
+    /// The distribution of every histogram is included in the report.
+    #[track_caller]
+    fn record<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: u64, labels: Iter);
+
+    /// Start a [Timer] that records how long it ran when dropped or stopped.
+    ///
+    /// The duration is added to the histogram of `key` in nanoseconds, see
+    /// [DebugMetricsTrait::record_duration], and `labels` are set when it finishes.
+    #[track_caller]
+    fn start_timer<Key: Into<String>, Iter: LabelIter>(
+        &mut self,
+        key: Key,
+        labels: Iter,
+    ) -> Timer<'_, Self> {
+        Timer {
+            key: key.into(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+                .collect(),
+            start: self.elapsed(),
+            location: Location::caller(),
+            finished: false,
+            debug_metrics: self,
+        }
+    }
+
+    /// Add a duration to the histogram of a metric in nanoseconds, recording an
+    /// [EventType::Timing].
+    ///
+    /// Used by [Timer], which passes the call site that started it as `location`.
+    fn record_duration<Key: Into<String>, Iter: LabelIter>(
+        &mut self,
+        key: Key,
+        duration: Duration,
+        panicked: bool,
+        labels: Iter,
+        location: &'static Location<'static>,
+    );
+
+    #[track_caller]
     fn set_label<Key: Into<String>, Value: Into<String>>(&mut self, key: Key, value: Value);
 
-    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<EventType>;
+    /// Set a label for this thread only.
+    ///
+    /// The events of this thread include it instead of a global label with the same key, so that
+    /// threads do not overwrite each other's context. Labels passed along with a change are still
+    /// global, and the report only lists the global labels. The labels of a thread are dropped
+    /// once it has exited.
+    #[track_caller]
+    fn set_thread_label<Key: Into<String>, Value: Into<String>>(&mut self, key: Key, value: Value);
+
+    /// Remove a label, recording an [EventType::LabelRemoved] if it was set.
+    #[track_caller]
+    fn remove_label<Key: Into<String>>(&mut self, key: Key);
+
+    /// Set a label until the returned [LabelGuard] is dropped, which restores the value the label
+    /// had before, or removes it if it had none.
+    #[track_caller]
+    fn scoped_label<Key: Into<String>, Value: Into<String>>(
+        &mut self,
+        key: Key,
+        value: Value,
+    ) -> LabelGuard<'_, Self> {
+        let key = key.into();
+        let location = Location::caller();
+        let previous = self.push_label(key.clone(), value.into(), location);
+        LabelGuard {
+            debug_metrics: self,
+            key,
+            previous,
+            location,
+        }
+    }
+
+    /// Set a label and return the value it had before.
+    ///
+    /// Used by [LabelGuard], prefer [DebugMetricsTrait::scoped_label].
+    fn push_label(
+        &mut self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) -> Option<String>;
+
+    /// Set a label back to `previous`, or remove it if that is `None`.
+    ///
+    /// Used by [LabelGuard], which passes the call site that created it as `location`.
+    fn pop_label(
+        &mut self,
+        key: String,
+        previous: Option<String>,
+        location: &'static Location<'static>,
+    );
+
+    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<Event>;
+
+    /// The count of every combination of labels that was passed with changes to `metric`, when
+    /// [DebugMetricsConfig::series] is on.
+    fn series<Key: Into<String>>(&self, metric: Key) -> Series;
+
+    /// The series of `metric` summed by `labels`, see [crate::sum_by].
+    fn sum_series_by<Key: Into<String>>(&self, metric: Key, labels: &[&str]) -> Series {
+        sum_by(&self.series(metric), labels)
+    }
+
+    /// How many times each call site that changed a metric or label touched each key.
+    fn call_sites(&self) -> CallSites;
+
+    /// Time since the collector was created, according to its [Clock].
+    fn elapsed(&self) -> Duration;
+
+    /// Enter a [Span] that stays open until dropped, nested in the spans already open on this
+    /// thread.
+    fn enter_span<Name: Into<String>>(&mut self, name: Name) -> Span<'_, Self> {
+        let depth = self.open_span(name.into());
+        Span {
+            debug_metrics: self,
+            depth,
+        }
+    }
+
+    /// Open a span on this thread and return how many spans were open before it.
+    ///
+    /// Used by [Span], prefer [DebugMetricsTrait::enter_span]. A `;` in the name is replaced by
+    /// `:`, as it separates the names in [SpanRecord::stack].
+    fn open_span(&mut self, name: String) -> usize;
+
+    /// Close the spans on this thread until only `depth` are left open.
+    fn close_span(&mut self, depth: usize);
+
+    /// The spans that were closed, in the order they were closed.
+    fn closed_spans(&self) -> Vec<SpanRecord>;
 
-    fn with_drop_hook<CallFn>(&mut self, call_fn: CallFn) -> DropHook<Self, CallFn>
+    /// The weight of every stack of spans, to write with [crate::write_folded_stacks] and render
+    /// as a flamegraph.
+    fn folded_stacks(&self, weight: StackWeight) -> BTreeMap<String, u64>;
+
+    /// Write all kept events, the current counts and the current labels, as human readable text.
+    ///
+    /// Unlike the report this can be done at any time, e.g. from a panic hook, and includes events
+    /// that would not be printed in the report.
+    fn dump(&mut self, target: DumpTarget) -> Result<(), DebugMetricsError>;
+
+    /// Write the report now instead of on drop, for collectors that are never dropped.
+    ///
+    /// The report is only written once, so later events are kept but not written, and
+    /// [DebugMetrics::finish] only hands back the writer.
+    fn flush_report(&mut self) -> Result<(), DebugMetricsError>;
+
+    /// Flush the events written by [OutputMode::Streaming] that have not been flushed yet.
+    ///
+    /// [crate::FlushPolicy] only flushes when an event is written, so this is how events are
+    /// flushed while the collector is idle, see [DebugMetricsSafe::spawn_stream_flusher].
+    fn flush_stream(&mut self);
+
+    /// Replace the file at `path` with the current metrics in the Prometheus text exposition
+    /// format, see [crate::PrometheusReporter].
+    ///
+    /// The file is written to a temporary file next to it first and then renamed, so scrapers never
+    /// see a partial file.
+    fn write_textfile(&self, path: &Path) -> Result<(), DebugMetricsError>;
+
+    fn with_drop_hook<CallFn>(&mut self, call_fn: CallFn) -> DropHook<'_, Self, CallFn>
     where
         CallFn: Fn(&mut Self),
     {
@@ -134,86 +812,398 @@ pub trait DebugMetricsTrait {
 impl<W: Write> DebugMetrics<W> {
     pub fn new(writer: W, config: DebugMetricsConfig) -> DebugMetrics<W> {
         DebugMetrics {
+            #[cfg(debug_metrics_enabled)]
             rules: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            conditional_rules: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            pattern_rules: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            triggers: RegexSet::empty(),
+            #[cfg(debug_metrics_enabled)]
+            triggered_by: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            last_event_values: None,
             counts: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            gauges: Default::default(),
+            histograms: Default::default(),
+            series: Default::default(),
             labels: Default::default(),
+            thread_labels: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            exited_threads: Default::default(),
             events: Default::default(),
+            evicted_events: 0,
+            #[cfg(debug_metrics_enabled)]
+            retained_bytes: 0,
+            #[cfg(debug_metrics_enabled)]
+            next_sequence: 0,
+            call_sites: Default::default(),
+            #[cfg(debug_metrics_enabled)]
+            open_spans: Default::default(),
+            closed_spans: Default::default(),
+            events_by_stack: Default::default(),
+            clock: Arc::new(MonotonicClock::new()),
+            #[cfg(debug_metrics_enabled)]
+            replaying: None,
+            stream: Default::default(),
+            reporter: match config.output_format {
+                OutputFormat::Text => Box::new(
+                    TextReporter::default()
+                        .with_event_times(config.report_event_times)
+                        .with_locations(config.report_locations)
+                        .with_threads(config.group_by_thread),
+                ),
+                OutputFormat::JsonLines => Box::new(
+                    JsonLinesReporter::default()
+                        .with_call_sites(config.report_locations)
+                        .with_threads(config.group_by_thread),
+                ),
+                OutputFormat::ChromeTrace => Box::new(ChromeTraceReporter::default()),
+                OutputFormat::Prometheus => Box::new(PrometheusReporter),
+                OutputFormat::Dot => Box::new(DotReporter::default()),
+            },
             drop_print: Default::default(),
-            output_writer: writer,
+            output_writer: Some(writer),
+            report_written: false,
             config,
         }
     }
 
+    /// Replace the clock used to time events, e.g. with a [crate::ManualClock] in tests.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Replace the reporter that was chosen by [DebugMetricsConfig::output_format].
+    pub fn with_reporter<R: Reporter + 'static>(mut self, reporter: R) -> Self {
+        self.reporter = Box::new(reporter);
+        self
+    }
+
     pub fn safe(self) -> DebugMetricsSafe<DebugMetrics<W>> {
         DebugMetricsSafe::new(self)
     }
 
-    fn matching_rules_for_regexes(
+    /// See [DebugMetricsSharded].
+    pub fn sharded(self) -> DebugMetricsSharded<W>
+    where
+        W: Send + 'static,
+    {
+        DebugMetricsSharded::new(self)
+    }
+
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Whether changes are collected, or ignored because [DebugMetricsConfig::enabled] is off.
+    #[cfg(debug_metrics_enabled)]
+    pub(crate) fn is_collecting(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// Write the report, unless [DebugMetricsTrait::flush_report] already did, and hand back the
+    /// writer.
+    ///
+    /// Unlike dropping, this surfaces writer failures to the caller.
+    pub fn finish(mut self) -> Result<W, DebugMetricsError> {
+        let mut writer = self
+            .output_writer
+            .take()
+            .expect("output writer is only taken by finish or drop");
+        if !std::mem::replace(&mut self.report_written, true) {
+            self.write_report(&mut writer)?;
+        }
+        Ok(writer)
+    }
+
+    /// Write all the recorded events that should be printed, followed by the summary.
+    ///
+    /// When streaming, the events have already been written, so only the summary is.
+    fn write_report(&mut self, writer: &mut W) -> std::io::Result<()> {
+        if let Some(e) = self.stream.error.take() {
+            return Err(e);
+        }
+        if self.config.output_mode == OutputMode::OnDrop {
+            let mut printed: Vec<&Event> = self
+                .events
+                .iter()
+                .filter(|e| Self::is_printed(&self.config, &self.drop_print, e))
+                .collect();
+            if self.config.group_by_thread {
+                let mut threads = HashMap::new();
+                for e in &printed {
+                    let next = threads.len();
+                    threads.entry(e.thread).or_insert(next);
+                }
+                // Stable, so the events of each thread stay in order
+                printed.sort_by_key(|e| threads[&e.thread]);
+            }
+            for e in printed {
+                self.reporter.report_event(writer, e)?;
+            }
+        }
+        let series = self.report_series();
+        let summary = Summary {
+            counts: &self.counts,
+            series: &series,
+            histograms: &self.histograms,
+            labels: &self.labels,
+            spans: &self.closed_spans,
+            call_sites: &self.call_sites,
+            evicted_events: self.evicted_events,
+        };
+        self.reporter.report_summary(writer, &summary)?;
+        writer.flush()
+    }
+
+    /// The series for the report, summed by [DebugMetricsConfig::series_sum_by].
+    fn report_series(&self) -> BTreeMap<String, Series> {
+        if self.config.series_sum_by.is_empty() {
+            return self.series.clone();
+        }
+        self.series
+            .iter()
+            .map(|(metric, series)| (metric.clone(), sum_by(series, self.config.series_sum_by)))
+            .collect()
+    }
+
+    fn write_dump(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "debug metrics dump, {} events kept, {} evicted:",
+            self.events.len(),
+            self.evicted_events
+        )?;
+        let mut reporter = TextReporter::default()
+            .with_event_times(true)
+            .with_locations(true);
+        for e in &self.events {
+            reporter.report_event(writer, e)?;
+        }
+        writeln!(writer, "counts: {:?}", self.counts)?;
+        for (key, histogram) in &self.histograms {
+            writeln!(writer, "histogram {key}: {histogram}")?;
+        }
+        writeln!(writer, "labels: {:?}", self.labels)?;
+        for (thread, labels) in &self.thread_labels {
+            writeln!(writer, "{thread:?} labels: {labels:?}")?;
+        }
+        writer.flush()
+    }
+
+    fn is_printed(
+        config: &DebugMetricsConfig,
+        drop_print: &BTreeSet<String>,
+        event: &Event,
+    ) -> bool {
+        matches!(event.event_type, EventType::Underflow { .. })
+            || config.process_all_events
+            || drop_print.contains(event.event_type.key())
+    }
+}
+
+#[cfg(debug_metrics_enabled)]
+impl<W: Write> DebugMetrics<W> {
+    /// Apply an operation that was buffered by [DebugMetricsSharded], as if it happened at the time
+    /// and on the thread it was buffered on.
+    pub(crate) fn replay(&mut self, op: BufferedOp) {
+        if !self.config.enabled {
+            return;
+        }
+        self.replaying = Some((op.elapsed, op.thread));
+        match op.kind {
+            OpKind::Metric {
+                key,
+                update,
+                labels,
+            } => self.update_metric(key, update, labels.into_iter(), op.location),
+            OpKind::Label { key, value } => self.update_label(key, value, op.location),
+            OpKind::ThreadLabel { key, value } => {
+                self.update_thread_label(key, value, op.location)
+            }
+            OpKind::RemoveLabel { key } => self.delete_label(key, op.location),
+            OpKind::Sample {
+                key,
+                sample,
+                labels,
+            } => self.record_sample(key, sample, labels.into_iter(), op.location),
+        }
+        self.replaying = None;
+    }
+
+    /// Every key in `counts`, `labels` and `thread_labels`, which the rules match against.
+    fn known_keys(&self) -> impl Iterator<Item = &String> {
+        self.counts
+            .keys()
+            .chain(self.labels.keys())
+            .chain(self.thread_labels.values().flat_map(BTreeMap::keys))
+    }
+
+    /// Make a key that is about to be inserted into `counts` or `labels` known to the rules.
+    ///
+    /// Must be called before the insert, so that keys are only ever matched once.
+    fn index_key(&mut self, key: &str) {
+        self.index_trigger(key);
+        if self.counts.contains_key(key)
+            || self.labels.contains_key(key)
+            || self.thread_labels.values().any(|l| l.contains_key(key))
+        {
+            return;
+        }
+        for rule in self.rules.values_mut() {
+            rule.index_key(key);
+        }
+        for rule in self.conditional_rules.values_mut().flatten() {
+            rule.index_key(key);
+        }
+        for pattern_rule in &mut self.pattern_rules {
+            pattern_rule.rule.index_key(key);
+        }
+    }
+
+    /// Register a rule triggered by a new pattern, and match every key that was seen against it.
+    fn push_pattern_rule(
+        &mut self,
+        trigger: KeyPattern,
+        mut rule: RecordingRule,
+    ) -> Result<(), DebugMetricsError> {
+        let pattern = trigger.pattern();
+        let regex = trigger.to_regex();
+        if let Err(source) = Regex::new(&regex) {
+            return Err(DebugMetricsError::InvalidTrigger { pattern, source });
+        }
+        let regexes: Vec<String> = self
+            .pattern_rules
+            .iter()
+            .map(|p| p.trigger.to_regex())
+            .chain([regex])
+            .collect();
+        self.triggers = RegexSet::new(&regexes)
+            .map_err(|source| DebugMetricsError::InvalidTrigger { pattern, source })?;
+        for key in self.known_keys() {
+            rule.index_key(key);
+        }
+        self.pattern_rules.push(PatternRule { trigger, rule });
+        let seen: Vec<String> = self
+            .known_keys()
+            .chain(self.histograms.keys())
+            .chain(self.triggered_by.keys())
+            .cloned()
+            .collect();
+        self.triggered_by.clear();
+        for key in seen {
+            self.index_trigger(&key);
+        }
+        Ok(())
+    }
+
+    /// Make a key known to the triggers of the pattern rules, so that they are only matched once.
+    fn index_trigger(&mut self, key: &str) {
+        if self.pattern_rules.is_empty() || self.triggered_by.contains_key(key) {
+            return;
+        }
+        let triggered = self.triggers.matches(key).into_iter().collect();
+        self.triggered_by.insert(key.to_string(), triggered);
+    }
+
+    /// The rules that `key` triggers, whether their conditions hold or not.
+    fn triggered_rules(&self, key: &str) -> impl Iterator<Item = &RecordingRule> {
+        let exact = self.rules.get(key);
+        let conditional = self
+            .conditional_rules
+            .get(key)
+            .map_or(&[][..], Vec::as_slice);
+        // Exact keys are the fast path, the pattern rules are only looked up when there are any
+        let triggered = match self.pattern_rules.is_empty() {
+            true => &[][..],
+            false => self.triggered_by.get(key).map_or(&[][..], Vec::as_slice),
+        };
+        exact
+            .into_iter()
+            .chain(conditional)
+            .chain(triggered.iter().map(|i| &self.pattern_rules[*i].rule))
+    }
+
+    /// The metrics and labels captured by the rules that `key` triggers and whose conditions hold
+    /// for `value`, or `None` if there are none.
+    fn captured_by_rules(
         &self,
-        regexes: &BTreeSet<&'static str>,
-        counts: &BTreeMap<String, u64>,
+        key: &str,
+        value: &Value,
+    ) -> Option<(BTreeMap<String, i64>, BTreeMap<String, String>)> {
+        let last = self.last_event_values.as_ref().and_then(|l| l.get(key));
+        let mut rules = self
+            .triggered_rules(key)
+            .filter(|rule| rule.conditions.iter().all(|c| c.holds(value, last)))
+            .peekable();
+        rules.peek()?;
+        let labels = self.event_labels();
+        let mut captured = (BTreeMap::new(), BTreeMap::new());
+        for rule in rules {
+            let (counts, labels) = Self::matching_rules_for_regexes(rule, &self.counts, &labels);
+            captured.0.extend(counts);
+            captured.1.extend(labels);
+        }
+        Some(captured)
+    }
+
+    fn matching_rules_for_regexes(
+        rule: &RecordingRule,
+        counts: &BTreeMap<String, i64>,
         labels: &BTreeMap<String, String>,
-    ) -> (BTreeMap<String, u64>, BTreeMap<String, String>) {
-        let mut found = BTreeSet::new();
+    ) -> (BTreeMap<String, i64>, BTreeMap<String, String>) {
         let mut count_ret = BTreeMap::new();
         let mut label_ret = BTreeMap::new();
-        for patt in regexes {
-            for (k, v) in counts {
-                // Create regex from pattern
-                let re = regex::Regex::new(patt).unwrap();
-                if !found.contains(k) && re.is_match(k) {
-                    found.insert(k);
-                    count_ret.insert(k.to_string(), *v);
-                }
-            }
-            for (k, v) in labels {
-                // Create regex from pattern
-                let re = regex::Regex::new(patt).unwrap();
-                if !found.contains(k) && re.is_match(k) {
-                    found.insert(k);
-                    label_ret.insert(k.to_string(), v.clone());
-                }
+        for k in &rule.matched_keys {
+            if let Some(v) = counts.get(k) {
+                count_ret.insert(k.to_string(), *v);
+            } else if let Some(v) = labels.get(k) {
+                label_ret.insert(k.to_string(), v.clone());
             }
         }
         (count_ret, label_ret)
     }
+
     fn maybe_include_all_labels_with_event(&self, event: &mut Option<EventType>) {
-        if self.config.all_labels_every_event {
-            if let Some(event) = event {
-                for (label_key, label_value) in &self.labels {
-                    match event {
-                        EventType::MetricChange {
-                            metric,
-                            count,
-                            dependencies,
-                            labels,
-                        } => {
-                            labels.insert(label_key.clone(), label_value.clone());
-                        }
-                        EventType::LabelChange {
-                            label,
-                            value,
-                            dependencies,
-                            labels,
-                        } => {
-                            labels.insert(label_key.clone(), label_value.clone());
-                        }
-                        _ => {
-                            unreachable!("Unexpected event type: {:?}", event);
-                        }
+        if !self.config.all_labels_every_event {
+            return;
+        }
+        if let Some(event) = event {
+            for (label_key, label_value) in self.event_labels().iter() {
+                match event {
+                    EventType::MetricChange { labels, .. } => {
+                        labels.insert(label_key.clone(), label_value.clone());
+                    }
+                    EventType::LabelChange { labels, .. }
+                    | EventType::LabelRemoved { labels, .. } => {
+                        labels.insert(label_key.clone(), label_value.clone());
+                    }
+                    EventType::Sample { labels, .. } | EventType::Timing { labels, .. } => {
+                        labels.insert(label_key.clone(), label_value.clone());
+                    }
+                    _ => {
+                        unreachable!("Unexpected event type: {:?}", event);
                     }
                 }
             }
         }
     }
     fn maybe_find_matching_rule(&self, event: &mut Option<EventType>, metric_or_label: &str) {
-        if let Some(rules) = self.rules.get(metric_or_label) {
-            let (matching_metrics, matching_labels) =
-                self.matching_rules_for_regexes(rules, &self.counts, &self.labels);
-            let c = self.get_metric_or_label(metric_or_label);
-            match c {
-                None => {}
-                Some(Value::Metric(c)) => {
+        if self.triggered_rules(metric_or_label).next().is_none() {
+            return;
+        }
+        let Some(value) = self.get_metric_or_label(metric_or_label) else {
+            return;
+        };
+        if let Some((matching_metrics, matching_labels)) =
+            self.captured_by_rules(metric_or_label, &value)
+        {
+            match value {
+                Value::Metric(c) => {
                     *event = Some(EventType::MetricChange {
                         metric: metric_or_label.to_string(),
                         count: c,
@@ -221,7 +1211,7 @@ impl<W: Write> DebugMetrics<W> {
                         labels: matching_labels,
                     });
                 }
-                Some(Value::Label(l)) => {
+                Value::Label(l) => {
                     *event = Some(EventType::LabelChange {
                         label: metric_or_label.to_string(),
                         value: l,
@@ -259,252 +1249,926 @@ impl<W: Write> DebugMetrics<W> {
         }
     }
 
+    fn push_event(&mut self, event_type: EventType, location: &'static Location<'static>) {
+        let (elapsed, thread) = match &self.replaying {
+            Some((elapsed, thread)) => (*elapsed, thread.clone()),
+            None => (self.clock.elapsed(), ThreadInfo::current()),
+        };
+        let span = self
+            .open_spans
+            .get(&thread.id)
+            .and_then(|spans| spans.last())
+            .map(|span| span.stack.clone());
+        if let Some(stack) = &span {
+            *self.events_by_stack.entry(stack.clone()).or_default() += 1;
+        }
+        if let Some(last_event_values) = &mut self.last_event_values {
+            let key = event_type.key().to_string();
+            match &event_type {
+                EventType::MetricChange { count, .. }
+                | EventType::CascadeMetricChange { count, .. } => {
+                    last_event_values.insert(key, Value::Metric(*count));
+                }
+                EventType::LabelChange { value, .. }
+                | EventType::CascadeLabelChange { value, .. } => {
+                    last_event_values.insert(key, Value::Label(value.clone()));
+                }
+                EventType::LabelRemoved { .. } => {
+                    last_event_values.remove(&key);
+                }
+                EventType::Sample { value, .. } => {
+                    let value = i64::try_from(*value).unwrap_or(i64::MAX);
+                    last_event_values.insert(key, Value::Metric(value));
+                }
+                EventType::Timing { duration, .. } => {
+                    let nanos = i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX);
+                    last_event_values.insert(key, Value::Metric(nanos));
+                }
+                EventType::Underflow { .. } => {}
+            }
+        }
+        let event = Event {
+            sequence: self.next_sequence,
+            elapsed,
+            location,
+            span,
+            thread: thread.id,
+            thread_name: thread.name.map(|name| name.to_string()),
+            event_type,
+        };
+        self.next_sequence += 1;
+        if let OutputMode::Streaming(flush_policy) = self.config.output_mode
+            && let Err(e) = self.stream_event(&event, flush_policy)
+        {
+            log::error!("Unable to stream debug metrics event, stopping the stream: {e}");
+            self.stream.error = Some(e);
+        }
+        self.retain_event(event);
+    }
+
+    fn retain_event(&mut self, event: Event) {
+        match self.config.retention {
+            RetentionPolicy::Unbounded => self.events.push_back(event),
+            RetentionPolicy::KeepFirst(n) => {
+                if self.events.len() < n {
+                    self.events.push_back(event);
+                } else {
+                    self.evicted_events += 1;
+                }
+            }
+            RetentionPolicy::KeepLast(n) => {
+                self.events.push_back(event);
+                while self.events.len() > n {
+                    self.events.pop_front();
+                    self.evicted_events += 1;
+                }
+            }
+            RetentionPolicy::ByteBudget(budget) => {
+                self.retained_bytes += event.approximate_size();
+                self.events.push_back(event);
+                while self.retained_bytes > budget {
+                    let Some(evicted) = self.events.pop_front() else {
+                        break;
+                    };
+                    self.retained_bytes -= evicted.approximate_size();
+                    self.evicted_events += 1;
+                }
+            }
+        }
+    }
+
+    fn stream_event(&mut self, event: &Event, flush_policy: FlushPolicy) -> std::io::Result<()> {
+        let (Some(writer), None) = (self.output_writer.as_mut(), self.stream.error.as_ref()) else {
+            return Ok(());
+        };
+        if self.report_written {
+            return Ok(());
+        }
+        if !Self::is_printed(&self.config, &self.drop_print, event) {
+            return Ok(());
+        }
+        self.reporter.report_event(writer, event)?;
+        self.stream.unflushed_events += 1;
+        let flush = match flush_policy {
+            FlushPolicy::EveryEvent => true,
+            FlushPolicy::EveryNEvents(n) => self.stream.unflushed_events >= n,
+            FlushPolicy::Interval(interval) => event.elapsed >= self.stream.last_flush + interval,
+        };
+        if flush {
+            writer.flush()?;
+            self.stream.unflushed_events = 0;
+            self.stream.last_flush = event.elapsed;
+        }
+        Ok(())
+    }
+
+    /// Apply a change to a metric along with the labels passed with it, recording the events.
+    fn update_metric<Iter: LabelIter>(
+        &mut self,
+        key: String,
+        update: MetricUpdate,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        self.touch_call_site(location, &key);
+        self.index_key(&key);
+        let mut underflow = self
+            .apply_update(&key, update)
+            .map(|(count, amount)| (count, amount, None));
+        if self.config.series {
+            let labels: Vec<(String, String)> = labels
+                .iter()
+                .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+                .collect();
+            let is_gauge = self.gauges.contains(&key);
+            let label_set: LabelSet = labels
+                .iter()
+                .filter(|(k, _)| !k.is_empty())
+                .cloned()
+                .collect();
+            let count = self
+                .series
+                .entry(key.clone())
+                .or_default()
+                .entry(label_set.clone())
+                .or_default();
+            if let Some((count, amount)) = Self::apply_update_to(count, update, is_gauge) {
+                underflow = Some((count, amount, Some(label_set)));
+            }
+            self.apply_call_labels(&key, labels.into_iter(), location);
+        } else {
+            self.apply_call_labels(&key, labels, location);
+        }
+        if let Some((count, amount, series_labels)) = underflow {
+            let event = EventType::Underflow {
+                metric: key.clone(),
+                count,
+                amount,
+                labels: series_labels.unwrap_or_else(|| self.event_labels().into_owned()),
+            };
+            self.push_event(event, location);
+        }
+        self.record_key_event(&key, location);
+    }
+
+    fn update_label(&mut self, key: String, value: String, location: &'static Location<'static>) {
+        self.touch_call_site(location, &key);
+        self.index_key(&key);
+        self.labels.insert(key.to_string(), value);
+        self.record_key_event(&key, location);
+    }
+
+    fn delete_label(&mut self, key: String, location: &'static Location<'static>) {
+        self.touch_call_site(location, &key);
+        let Some(value) = self.labels.remove(&key) else {
+            return;
+        };
+        // The label is no longer in `labels`, so the rules and config are checked here
+        let recorded = match self.captured_by_rules(&key, &Value::Label(value.clone())) {
+            Some(captured) => Some(captured),
+            None if self.config.process_all_events => Some(Default::default()),
+            None => None,
+        };
+        let mut event = recorded.map(|(dependencies, labels)| EventType::LabelRemoved {
+            label: key,
+            value,
+            dependencies,
+            labels,
+        });
+        self.maybe_include_all_labels_with_event(&mut event);
+        if let Some(event) = event {
+            self.push_event(event, location);
+        }
+    }
+
+    fn update_thread_label(
+        &mut self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) {
+        self.touch_call_site(location, &key);
+        self.index_key(&key);
+        // When replaying, the thread was registered when the op was buffered, and its labels are
+        // forgotten once the ops it buffered before exiting are replayed
+        if self.replaying.is_none() {
+            let exited = self.take_exited_threads();
+            self.forget_threads(exited);
+            notify_on_exit(&self.exited_threads);
+        }
+        let thread = self.event_thread();
+        self.thread_labels
+            .entry(thread)
+            .or_default()
+            .insert(key.clone(), value);
+        self.record_key_event(&key, location);
+    }
+
+    /// Where threads that set thread labels are pushed when they exit.
+    pub(crate) fn exited_threads(&self) -> ExitedThreads {
+        self.exited_threads.clone()
+    }
+
+    /// The threads that exited since this was last called.
+    pub(crate) fn take_exited_threads(&self) -> Vec<ThreadId> {
+        std::mem::take(
+            &mut *self
+                .exited_threads
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
+        )
+    }
+
+    /// Remove the thread labels of threads that have exited, so they do not pile up.
+    pub(crate) fn forget_threads(&mut self, threads: Vec<ThreadId>) {
+        for thread in threads {
+            self.thread_labels.remove(&thread);
+        }
+    }
+
+    /// The thread the event being recorded happened on.
+    fn event_thread(&self) -> ThreadId {
+        match &self.replaying {
+            Some((_, thread)) => thread.id,
+            None => std::thread::current().id(),
+        }
+    }
+
+    /// The labels of the event being recorded: the global labels, overridden by those of its
+    /// thread.
+    fn event_labels(&self) -> Cow<'_, BTreeMap<String, String>> {
+        match self.thread_labels.get(&self.event_thread()) {
+            Some(thread_labels) => {
+                let mut labels = self.labels.clone();
+                labels.extend(thread_labels.iter().map(|(k, v)| (k.clone(), v.clone())));
+                Cow::Owned(labels)
+            }
+            None => Cow::Borrowed(&self.labels),
+        }
+    }
+
+    /// Set the labels passed along with a change to `key`, recording them as caused by it.
+    fn apply_call_labels<Iter: LabelIter>(
+        &mut self,
+        key: &str,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        for (label_key, label_value) in labels.iter() {
+            let label_key: String = label_key.as_ref().to_string();
+            let label_value: String = label_value.as_ref().to_string();
+            if label_key.is_empty() {
+                // TODO this is a hack, because Vecs need a type and sometimes its just easier
+                // with empty strings. It will be fixed with a proper iterator API.
+                continue;
+            }
+            self.touch_call_site(location, &label_key);
+            self.index_key(&label_key);
+            self.labels.insert(label_key.to_string(), label_value);
+            let mut event = None;
+            self.maybe_find_matching_rule(&mut event, &label_key);
+            self.maybe_include_all_events(&mut event, &label_key);
+            self.maybe_include_all_labels_with_event(&mut event);
+            if let Some(event) = event {
+                let event = event.promote_to_cascade(key);
+                self.push_event(event, location);
+            }
+        }
+    }
+
+    /// Add a value to the histogram of a metric along with the labels passed with it.
+    fn record_sample<Iter: LabelIter>(
+        &mut self,
+        key: String,
+        sample: SampleValue,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        self.touch_call_site(location, &key);
+        self.index_trigger(&key);
+        let value = match sample {
+            SampleValue::Value(value) => value,
+            SampleValue::Duration { duration, .. } => {
+                u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+            }
+        };
+        let buckets = self.config.histogram_buckets;
+        self.histograms
+            .entry(key.clone())
+            .or_insert_with(|| Histogram::new(buckets))
+            .record(value);
+        self.apply_call_labels(&key, labels, location);
+        // Samples are not in `counts` or `labels`, so the rules and config are checked here
+        let sampled = Value::Metric(i64::try_from(value).unwrap_or(i64::MAX));
+        let recorded = match self.captured_by_rules(&key, &sampled) {
+            Some(captured) => Some(captured),
+            None if self.config.process_all_events => Some(Default::default()),
+            None => None,
+        };
+        let mut event = recorded.map(|(dependencies, labels)| match sample {
+            SampleValue::Value(value) => EventType::Sample {
+                metric: key,
+                value,
+                dependencies,
+                labels,
+            },
+            SampleValue::Duration { duration, panicked } => EventType::Timing {
+                metric: key,
+                duration,
+                panicked,
+                dependencies,
+                labels,
+            },
+        });
+        self.maybe_include_all_labels_with_event(&mut event);
+        if let Some(event) = event {
+            self.push_event(event, location);
+        }
+    }
+
+    /// Returns the count before and the amount subtracted, if a counter would have underflowed.
+    fn apply_update(&mut self, key: &str, update: MetricUpdate) -> Option<(i64, u64)> {
+        if let MetricUpdate::SetGauge(_) = update {
+            self.gauges.insert(key.to_string());
+        }
+        let is_gauge = self.gauges.contains(key);
+        let count = self.counts.entry(key.to_string()).or_default();
+        Self::apply_update_to(count, update, is_gauge)
+    }
+
+    fn apply_update_to(
+        count: &mut i64,
+        update: MetricUpdate,
+        is_gauge: bool,
+    ) -> Option<(i64, u64)> {
+        match update {
+            MetricUpdate::Add(n) => *count = count.saturating_add_unsigned(n),
+            MetricUpdate::Sub(n) if is_gauge => *count = count.saturating_sub_unsigned(n),
+            MetricUpdate::Sub(n) => {
+                let before = *count;
+                match u64::try_from(before) {
+                    Ok(current) if current >= n => *count = before - n as i64,
+                    _ => {
+                        *count = 0;
+                        return Some((before, n));
+                    }
+                }
+            }
+            MetricUpdate::Set(value) => *count = i64::try_from(value).unwrap_or(i64::MAX),
+            MetricUpdate::SetGauge(value) => *count = value,
+        }
+        None
+    }
+
+    /// Record the event for a changed key, if a rule or the config asks for one.
+    fn record_key_event(&mut self, key: &str, location: &'static Location<'static>) {
+        let mut event = None;
+        self.maybe_find_matching_rule(&mut event, key);
+        self.maybe_include_all_events(&mut event, key);
+        self.maybe_include_all_labels_with_event(&mut event);
+        if let Some(event) = event {
+            self.push_event(event, location);
+        }
+    }
+
+    fn touch_call_site(&mut self, location: &'static Location<'static>, key: &str) {
+        let keys = self.call_sites.entry(location).or_default();
+        // Looked up first, so that only the first touch of a key from a call site allocates
+        match keys.get_mut(key) {
+            Some(touches) => *touches += 1,
+            None => {
+                keys.insert(key.to_string(), 1);
+            }
+        }
+    }
+
     fn get_metric_or_label(&self, key: &str) -> Option<Value> {
         if let Some(count) = self.counts.get(key) {
             Some(Value::Metric(*count))
-        } else if let Some(label) = self.labels.get(key) {
-            Some(Value::Label(label.clone()))
         } else {
-            None
+            self.thread_labels
+                .get(&self.event_thread())
+                .and_then(|labels| labels.get(key))
+                .or_else(|| self.labels.get(key))
+                .map(|label| Value::Label(label.clone()))
         }
     }
 }
 
 impl<W: Write> DebugMetricsTrait for DebugMetrics<W> {
-    /// Include regex recording rules.
-    fn add_recording_rule<Key: Into<String>>(&mut self, metric: Key, additional: &[&'static str]) {
-        #[cfg(debug_assertions)]
+    fn try_add_recording_rule<Key: Into<String>>(
+        &mut self,
+        metric: Key,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        #[cfg(debug_metrics_enabled)]
         {
             let metric = metric.into();
-            if let Some(existing) = self.rules.get_mut(&metric) {
-                existing.extend(additional);
-            } else {
-                let mut set = BTreeSet::new();
-                set.extend(additional);
-                self.rules.insert(metric, set);
+            let existing = self.rules.remove(&metric);
+            let is_new = existing.is_none();
+            let mut rule = existing.unwrap_or_else(RecordingRule::new);
+            let changed = rule.extend(&metric, additional);
+            if let Ok(true) = changed {
+                // Only existing keys need a full scan, new keys are indexed as they are inserted
+                rule.matched_keys.clear();
+                for key in self.known_keys() {
+                    rule.index_key(key);
+                }
+            }
+            // A new rule is only registered once its patterns are known to be valid
+            if !is_new || changed.is_ok() {
+                self.rules.insert(metric, rule);
             }
+            changed?;
         }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (metric, additional);
+        Ok(())
+    }
+
+    fn try_add_pattern_recording_rule(
+        &mut self,
+        trigger: KeyPattern,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        #[cfg(debug_metrics_enabled)]
+        {
+            let metric = trigger.pattern();
+            let existing = self
+                .pattern_rules
+                .iter()
+                .position(|p| p.trigger == trigger && p.rule.conditions.is_empty());
+            if let Some(i) = existing {
+                if self.pattern_rules[i].rule.extend(metric, additional)? {
+                    let rule = &self.pattern_rules[i].rule;
+                    let matched_keys = self
+                        .known_keys()
+                        .filter(|key| rule.regex_set.is_match(key))
+                        .cloned()
+                        .collect();
+                    self.pattern_rules[i].rule.matched_keys = matched_keys;
+                }
+                return Ok(());
+            }
+            let mut rule = RecordingRule::new();
+            rule.extend(metric, additional)?;
+            self.push_pattern_rule(trigger, rule)?;
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (trigger, additional);
+        Ok(())
+    }
+
+    fn try_add_rule(&mut self, rule: RecordingRuleBuilder) -> Result<(), DebugMetricsError> {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if rule.conditions.is_empty() {
+                return match rule.trigger {
+                    RuleTrigger::Key(key) => self.try_add_recording_rule(key, &rule.captures),
+                    RuleTrigger::Pattern(trigger) => {
+                        self.try_add_pattern_recording_rule(trigger, &rule.captures)
+                    }
+                };
+            }
+            let metric = rule.name();
+            let mut recording_rule = RecordingRule::new();
+            recording_rule.extend(metric, &rule.captures)?;
+            recording_rule.conditions = rule
+                .conditions
+                .iter()
+                .map(|condition| RuleCondition::new(metric, *condition))
+                .collect::<Result<_, _>>()?;
+            if rule.conditions.contains(&Condition::Changed) && self.last_event_values.is_none() {
+                self.last_event_values = Some(Default::default());
+            }
+            match rule.trigger {
+                RuleTrigger::Key(key) => {
+                    for known in self.known_keys() {
+                        recording_rule.index_key(known);
+                    }
+                    self.conditional_rules
+                        .entry(key)
+                        .or_default()
+                        .push(recording_rule);
+                }
+                RuleTrigger::Pattern(trigger) => self.push_pattern_rule(trigger, recording_rule)?,
+            }
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = rule;
+        Ok(())
     }
 
     fn add_drop_hook<Key: Into<String>>(&mut self, key: Key) {
-        #[cfg(debug_assertions)]
+        #[cfg(debug_metrics_enabled)]
         {
             let key = key.into();
             self.drop_print.insert(key);
         }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = key;
     }
 
+    #[track_caller]
     fn inc<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, labels: Iter) {
-        #[cfg(debug_assertions)]
+        #[cfg(debug_metrics_enabled)]
         {
-            let key = key.into();
-            // Increment
-            *self.counts.entry(key.to_string()).or_default() += 1;
-            for (label_key, label_value) in labels.iter() {
-                let label_key: String = label_key.as_ref().to_string();
-                let label_value: String = label_value.as_ref().to_string();
-                if label_key.is_empty() {
-                    // TODO this is a hack, because Vecs need a type and sometimes its just easier
-                    // with empty strings. It will be fixed with a proper iterator API.
-                    continue;
-                }
-                self.labels.insert(label_key.to_string(), label_value);
-                let mut event = None;
-                self.maybe_find_matching_rule(&mut event, &label_key);
-                self.maybe_include_all_events(&mut event, &label_key);
-                self.maybe_include_all_labels_with_event(&mut event);
-                if let Some(event) = event {
-                    let event = event.promote_to_cascade(&key);
-                    self.events.push(event);
-                }
+            if !self.config.enabled {
+                return;
             }
-            let mut event = None;
-            self.maybe_find_matching_rule(&mut event, &key);
-            self.maybe_include_all_events(&mut event, &key);
-            self.maybe_include_all_labels_with_event(&mut event);
-            if let Some(event) = event {
-                self.events.push(event);
+            self.update_metric(key.into(), MetricUpdate::Add(1), labels, Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, labels);
+    }
+
+    #[track_caller]
+    fn dec<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
             }
+            self.update_metric(key.into(), MetricUpdate::Sub(1), labels, Location::caller());
         }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, labels);
     }
 
-    fn set<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: u64, mut labels: Iter) {
-        #[cfg(debug_assertions)]
+    #[track_caller]
+    fn add<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, n: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
         {
-            let key = key.into();
-            // Increment
-            *self.counts.entry(key.to_string()).or_default() = value;
-            for (label_key, label_value) in labels.iter() {
-                let label_key: String = label_key.as_ref().to_string();
-                let label_value: String = label_value.as_ref().to_string();
-                self.labels.insert(label_key.to_string(), label_value);
-                let mut event = None;
-                self.maybe_find_matching_rule(&mut event, &label_key);
-                self.maybe_include_all_events(&mut event, &label_key);
-                self.maybe_include_all_labels_with_event(&mut event);
-                if let Some(event) = event {
-                    let event = event.promote_to_cascade(&key);
-                    self.events.push(event);
-                }
+            if !self.config.enabled {
+                return;
             }
-            let mut event = None;
-            self.maybe_find_matching_rule(&mut event, &key);
-            self.maybe_include_all_events(&mut event, &key);
-            self.maybe_include_all_labels_with_event(&mut event);
-            if let Some(event) = event {
-                self.events.push(event);
+            self.update_metric(key.into(), MetricUpdate::Add(n), labels, Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, n, labels);
+    }
+
+    #[track_caller]
+    fn sub<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, n: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            self.update_metric(key.into(), MetricUpdate::Sub(n), labels, Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, n, labels);
+    }
+
+    #[track_caller]
+    fn set<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            self.update_metric(
+                key.into(),
+                MetricUpdate::Set(value),
+                labels,
+                Location::caller(),
+            );
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
+    }
+
+    #[track_caller]
+    fn set_gauge<Key: Into<String>, Iter: LabelIter>(
+        &mut self,
+        key: Key,
+        value: i64,
+        labels: Iter,
+    ) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
             }
+            self.update_metric(
+                key.into(),
+                MetricUpdate::SetGauge(value),
+                labels,
+                Location::caller(),
+            );
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
+    }
+
+    #[track_caller]
+    fn record<Key: Into<String>, Iter: LabelIter>(&mut self, key: Key, value: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            self.record_sample(
+                key.into(),
+                SampleValue::Value(value),
+                labels,
+                Location::caller(),
+            );
         }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
     }
 
+    fn record_duration<Key: Into<String>, Iter: LabelIter>(
+        &mut self,
+        key: Key,
+        duration: Duration,
+        panicked: bool,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            let sample = SampleValue::Duration { duration, panicked };
+            self.record_sample(key.into(), sample, labels, location);
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, duration, panicked, labels, location);
+    }
+
+    #[track_caller]
     fn set_label<Key: Into<String>, Value: Into<String>>(&mut self, key: Key, value: Value) {
-        #[cfg(debug_assertions)]
+        #[cfg(debug_metrics_enabled)]
         {
-            let key = key.into();
-            let value = value.into();
-            self.labels.insert(key.to_string(), value.to_string());
-            let mut event = None;
-            self.maybe_find_matching_rule(&mut event, &key);
-            self.maybe_include_all_events(&mut event, &key);
-            self.maybe_include_all_labels_with_event(&mut event);
-            if let Some(event) = event {
-                self.events.push(event);
+            if !self.config.enabled {
+                return;
+            }
+            self.update_label(key.into(), value.into(), Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value);
+    }
+
+    #[track_caller]
+    fn set_thread_label<Key: Into<String>, Value: Into<String>>(&mut self, key: Key, value: Value) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            self.update_thread_label(key.into(), value.into(), Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value);
+    }
+
+    #[track_caller]
+    fn remove_label<Key: Into<String>>(&mut self, key: Key) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            self.delete_label(key.into(), Location::caller());
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = key;
+    }
+
+    fn push_label(
+        &mut self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) -> Option<String> {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return None;
+            }
+            let previous = self.labels.get(&key).cloned();
+            self.update_label(key, value, location);
+            previous
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        {
+            let _ = (key, value, location);
+            None
+        }
+    }
+
+    fn pop_label(
+        &mut self,
+        key: String,
+        previous: Option<String>,
+        location: &'static Location<'static>,
+    ) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return;
+            }
+            match previous {
+                Some(value) => self.update_label(key, value, location),
+                None => self.delete_label(key, location),
             }
         }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, previous, location);
     }
 
-    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<EventType> {
-        #[cfg(debug_assertions)]
+    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<Event> {
+        #[cfg(debug_metrics_enabled)]
         {
             let key = key.into();
             self.events
                 .iter()
-                .filter(|e| match e {
-                    EventType::MetricChange {
-                        metric,
-                        count,
-                        dependencies,
-                        labels,
-                    } => metric == &key,
-                    EventType::LabelChange {
-                        label,
-                        value,
-                        dependencies,
-                        labels,
-                    } => label == &key,
-                    EventType::CascadeMetricChange {
-                        cause,
-                        metric,
-                        count,
-                        dependencies,
-                        labels,
-                    } => metric == &key || cause == &key,
-                    EventType::CascadeLabelChange {
-                        cause,
-                        label,
-                        value,
-                        dependencies,
-                        labels,
-                    } => label == &key || cause == &key,
+                .filter(|e| match &e.event_type {
+                    EventType::MetricChange { metric, .. } => metric == &key,
+                    EventType::LabelChange { label, .. } => label == &key,
+                    EventType::LabelRemoved { label, .. } => label == &key,
+                    EventType::CascadeMetricChange { cause, metric, .. } => {
+                        metric == &key || cause == &key
+                    }
+                    EventType::CascadeLabelChange { cause, label, .. } => {
+                        label == &key || cause == &key
+                    }
+                    EventType::Sample { metric, .. } => metric == &key,
+                    EventType::Timing { metric, .. } => metric == &key,
+                    EventType::Underflow { metric, .. } => metric == &key,
                 })
                 .cloned()
                 .collect()
         }
-        #[cfg(not(debug_assertions))]
+        #[cfg(not(debug_metrics_enabled))]
         {
+            let _ = key;
             Vec::new()
         }
     }
+
+    fn series<Key: Into<String>>(&self, metric: Key) -> Series {
+        self.series.get(&metric.into()).cloned().unwrap_or_default()
+    }
+
+    fn call_sites(&self) -> CallSites {
+        self.call_sites.clone()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    fn open_span(&mut self, name: String) -> usize {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if !self.config.enabled {
+                return 0;
+            }
+            let start = self.clock.elapsed();
+            let spans = self
+                .open_spans
+                .entry(std::thread::current().id())
+                .or_default();
+            let name = name.replace(';', ":");
+            let stack = match spans.last() {
+                Some(parent) => format!("{};{name}", parent.stack),
+                None => name,
+            };
+            spans.push(OpenSpan { stack, start });
+            spans.len() - 1
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        {
+            let _ = name;
+            0
+        }
+    }
+
+    fn close_span(&mut self, depth: usize) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            let thread = std::thread::current().id();
+            let Some(spans) = self.open_spans.get_mut(&thread) else {
+                return;
+            };
+            let end = self.clock.elapsed();
+            let thread_name = std::thread::current().name().map(str::to_string);
+            while spans.len() > depth {
+                let Some(span) = spans.pop() else {
+                    break;
+                };
+                self.closed_spans.push(SpanRecord {
+                    stack: span.stack,
+                    thread,
+                    thread_name: thread_name.clone(),
+                    start: span.start,
+                    end,
+                });
+            }
+            if spans.is_empty() {
+                self.open_spans.remove(&thread);
+            }
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = depth;
+    }
+
+    fn closed_spans(&self) -> Vec<SpanRecord> {
+        self.closed_spans.clone()
+    }
+
+    fn folded_stacks(&self, weight: StackWeight) -> BTreeMap<String, u64> {
+        match weight {
+            StackWeight::Time => self_time_by_stack(&self.closed_spans),
+            StackWeight::Events => self.events_by_stack.clone(),
+        }
+    }
+
+    fn dump(&mut self, target: DumpTarget) -> Result<(), DebugMetricsError> {
+        match target {
+            DumpTarget::Writer => {
+                let Some(mut writer) = self.output_writer.take() else {
+                    return Ok(());
+                };
+                let result = self.write_dump(&mut writer);
+                self.output_writer = Some(writer);
+                result?;
+            }
+            DumpTarget::Stderr => self.write_dump(&mut std::io::stderr().lock())?,
+        }
+        Ok(())
+    }
+
+    fn write_textfile(&self, path: &Path) -> Result<(), DebugMetricsError> {
+        let series = self.report_series();
+        let summary = Summary {
+            counts: &self.counts,
+            series: &series,
+            histograms: &self.histograms,
+            labels: &self.labels,
+            spans: &self.closed_spans,
+            call_sites: &self.call_sites,
+            evicted_events: self.evicted_events,
+        };
+        let mut contents = Vec::new();
+        PrometheusReporter.report_summary(&mut contents, &summary)?;
+        write_file_atomically(path, &contents)?;
+        Ok(())
+    }
+
+    fn flush_report(&mut self) -> Result<(), DebugMetricsError> {
+        if std::mem::replace(&mut self.report_written, true) {
+            return Ok(());
+        }
+        let Some(mut writer) = self.output_writer.take() else {
+            return Ok(());
+        };
+        let result = self.write_report(&mut writer);
+        self.output_writer = Some(writer);
+        result?;
+        Ok(())
+    }
+
+    fn flush_stream(&mut self) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            if self.stream.unflushed_events == 0 || self.report_written {
+                return;
+            }
+            let (Some(writer), None) = (self.output_writer.as_mut(), self.stream.error.as_ref())
+            else {
+                return;
+            };
+            if let Err(e) = writer.flush() {
+                log::error!("Unable to flush debug metrics stream, stopping the stream: {e}");
+                self.stream.error = Some(e);
+                return;
+            }
+            self.stream.unflushed_events = 0;
+            self.stream.last_flush = self.clock.elapsed();
+        }
+    }
 }
 
 impl<W: Write> Drop for DebugMetrics<W> {
     fn drop(&mut self) {
-        for e in self.events.iter() {
-            match e {
-                EventType::MetricChange {
-                    metric,
-                    count,
-                    dependencies,
-                    labels,
-                } => {
-                    if self.config.process_all_events | self.drop_print.contains(metric) {
-                        let mut all_deps = BTreeMap::new();
-                        dependencies.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.to_string());
-                        });
-                        labels.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.clone());
-                        });
-                        self.output_writer
-                            .write_fmt(format_args!("{metric}: {count} :: {all_deps:?}\n"))
-                            .unwrap();
-                    }
-                }
-                EventType::LabelChange {
-                    label,
-                    value,
-                    dependencies,
-                    labels,
-                } => {
-                    if self.config.process_all_events | self.drop_print.contains(label) {
-                        let mut all_deps = BTreeMap::new();
-                        dependencies.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.to_string());
-                        });
-                        labels.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.clone());
-                        });
-                        self.output_writer
-                            .write_fmt(format_args!("{label}: {value} :: {all_deps:?}\n"))
-                            .unwrap();
-                    }
-                }
-                EventType::CascadeMetricChange {
-                    cause,
-                    metric,
-                    count,
-                    dependencies,
-                    labels,
-                } => {
-                    if self.config.process_all_events | self.drop_print.contains(metric) {
-                        let mut all_deps = BTreeMap::new();
-                        dependencies.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.to_string());
-                        });
-                        labels.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.clone());
-                        });
-                        self.output_writer
-                            .write_fmt(format_args!(
-                                "{metric} (caused by {cause}): {count} :: {all_deps:?}\n"
-                            ))
-                            .unwrap();
-                    }
-                }
-                EventType::CascadeLabelChange {
-                    cause,
-                    label,
-                    value,
-                    dependencies,
-                    labels,
-                } => {
-                    if self.config.process_all_events | self.drop_print.contains(label) {
-                        let mut all_deps = BTreeMap::new();
-                        dependencies.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.to_string());
-                        });
-                        labels.iter().for_each(|(k, v)| {
-                            all_deps.insert(k.clone(), v.clone());
-                        });
-                        self.output_writer
-                            .write_fmt(format_args!(
-                                "{label} (caused by {cause}): {value} :: {all_deps:?}\n"
-                            ))
-                            .unwrap();
-                    }
-                }
-            }
+        let Some(mut writer) = self.output_writer.take() else {
+            return;
+        };
+        if self.report_written {
+            return;
+        }
+        if std::thread::panicking() {
+            log::warn!("Skipping debug metrics report, because the thread is panicking");
+            return;
+        }
+        if let Err(e) = self.write_report(&mut writer) {
+            log::error!("Unable to write debug metrics report: {e}");
         }
-        self.output_writer.flush().unwrap();
     }
 }
@@ -1,7 +1,23 @@
-use crate::debug_metrics::{DebugMetricsTrait, EventType};
+use crate::debug_metrics::{CallSites, DebugMetricsTrait, DumpTarget, Event, KeyPattern};
 use crate::drop_hook_safe::DropHookSafe;
+use crate::error::DebugMetricsError;
+use crate::label_guard_safe::LabelGuardSafe;
 use crate::label_iter::LabelIter;
-use std::sync::{Arc, Mutex};
+use crate::panic_hook::PanicHookGuard;
+use crate::prometheus::TextfileWriter;
+use crate::rule::RecordingRuleBuilder;
+use crate::series::{sum_by, Series};
+use crate::span::{SpanRecord, StackWeight};
+use crate::span_safe::SpanSafe;
+use crate::stream_flusher::StreamFlusher;
+use crate::timer_safe::TimerSafe;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::panic::Location;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Duration;
 
 pub struct DebugMetricsSafe<DM: DebugMetricsTrait> {
     inner: Arc<Mutex<DM>>,
@@ -17,17 +33,193 @@ impl<DM: DebugMetricsTrait> Clone for DebugMetricsSafe<DM> {
 }
 
 pub trait DebugMetricsSafeTrait: Clone {
-    fn add_recording_rule<Key: Into<String>>(&self, metric: Key, additional: &[&'static str]);
+    /// Include regex recording rules.
+    ///
+    /// Panics if any of the patterns is not a valid regex.
+    #[track_caller]
+    fn add_recording_rule<Key: Into<String>>(&self, metric: Key, additional: &[&'static str]) {
+        if let Err(e) = self.try_add_recording_rule(metric, additional) {
+            panic!("{e}");
+        }
+    }
+
+    /// Include regex recording rules, returning an error if any of the patterns is invalid.
+    fn try_add_recording_rule<Key: Into<String>>(
+        &self,
+        metric: Key,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError>;
+
+    /// See [DebugMetricsTrait::add_pattern_recording_rule].
+    #[track_caller]
+    fn add_pattern_recording_rule(&self, trigger: KeyPattern, additional: &[&'static str]) {
+        if let Err(e) = self.try_add_pattern_recording_rule(trigger, additional) {
+            panic!("{e}");
+        }
+    }
+
+    /// See [DebugMetricsTrait::try_add_pattern_recording_rule].
+    fn try_add_pattern_recording_rule(
+        &self,
+        trigger: KeyPattern,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError>;
+
+    /// See [DebugMetricsTrait::add_rule].
+    #[track_caller]
+    fn add_rule(&self, rule: RecordingRuleBuilder) {
+        if let Err(e) = self.try_add_rule(rule) {
+            panic!("{e}");
+        }
+    }
+
+    /// See [DebugMetricsTrait::try_add_rule].
+    fn try_add_rule(&self, rule: RecordingRuleBuilder) -> Result<(), DebugMetricsError>;
 
     fn add_drop_hook<Key: Into<String>>(&self, key: Key);
 
+    #[track_caller]
     fn inc<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter);
 
+    /// See [DebugMetricsTrait::dec].
+    #[track_caller]
+    fn dec<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter);
+
+    #[track_caller]
+    fn add<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter);
+
+    /// See [DebugMetricsTrait::sub].
+    #[track_caller]
+    fn sub<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter);
+
+    #[track_caller]
     fn set<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter);
 
+    /// See [DebugMetricsTrait::set_gauge].
+    #[track_caller]
+    fn set_gauge<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: i64, labels: Iter);
+
+    /// See [DebugMetricsTrait::record].
+    #[track_caller]
+    fn record<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter);
+
+    /// Start a [TimerSafe], see [DebugMetricsTrait::start_timer].
+    #[track_caller]
+    fn start_timer<Key: Into<String>, Iter: LabelIter>(
+        &self,
+        key: Key,
+        labels: Iter,
+    ) -> TimerSafe<Self> {
+        TimerSafe {
+            key: key.into(),
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+                .collect(),
+            start: self.elapsed(),
+            location: Location::caller(),
+            finished: false,
+            debug_metrics: self.clone(),
+        }
+    }
+
+    /// See [DebugMetricsTrait::record_duration].
+    fn record_duration<Key: Into<String>, Iter: LabelIter>(
+        &self,
+        key: Key,
+        duration: Duration,
+        panicked: bool,
+        labels: Iter,
+        location: &'static Location<'static>,
+    );
+
+    #[track_caller]
     fn set_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value);
 
-    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<EventType>;
+    /// See [DebugMetricsTrait::set_thread_label].
+    #[track_caller]
+    fn set_thread_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value);
+
+    /// See [DebugMetricsTrait::remove_label].
+    #[track_caller]
+    fn remove_label<Key: Into<String>>(&self, key: Key);
+
+    /// See [DebugMetricsTrait::scoped_label].
+    #[track_caller]
+    fn scoped_label<Key: Into<String>, Value: Into<String>>(
+        &self,
+        key: Key,
+        value: Value,
+    ) -> LabelGuardSafe<Self> {
+        let key = key.into();
+        let location = Location::caller();
+        LabelGuardSafe {
+            previous: self.push_label(key.clone(), value.into(), location),
+            key,
+            location,
+            debug_metrics: self.clone(),
+        }
+    }
+
+    /// See [DebugMetricsTrait::push_label].
+    fn push_label(
+        &self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) -> Option<String>;
+
+    /// See [DebugMetricsTrait::pop_label].
+    fn pop_label(
+        &self,
+        key: String,
+        previous: Option<String>,
+        location: &'static Location<'static>,
+    );
+
+    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<Event>;
+
+    /// See [DebugMetricsTrait::series].
+    fn series<Key: Into<String>>(&self, metric: Key) -> Series;
+
+    /// See [DebugMetricsTrait::sum_series_by].
+    fn sum_series_by<Key: Into<String>>(&self, metric: Key, labels: &[&str]) -> Series {
+        sum_by(&self.series(metric), labels)
+    }
+
+    /// How many times each call site that changed a metric or label touched each key.
+    fn call_sites(&self) -> CallSites;
+
+    /// See [DebugMetricsTrait::elapsed].
+    fn elapsed(&self) -> Duration;
+
+    /// Enter a [SpanSafe], see [DebugMetricsTrait::enter_span].
+    fn enter_span<Name: Into<String>>(&self, name: Name) -> SpanSafe<Self> {
+        SpanSafe {
+            depth: self.open_span(name.into()),
+            debug_metrics: self.clone(),
+            not_send: PhantomData,
+        }
+    }
+
+    /// See [DebugMetricsTrait::open_span].
+    fn open_span(&self, name: String) -> usize;
+
+    /// See [DebugMetricsTrait::close_span].
+    fn close_span(&self, depth: usize);
+
+    fn closed_spans(&self) -> Vec<SpanRecord>;
+
+    /// See [DebugMetricsTrait::folded_stacks].
+    fn folded_stacks(&self, weight: StackWeight) -> BTreeMap<String, u64>;
+
+    /// Write the report now instead of when the last clone is dropped.
+    ///
+    /// See [DebugMetricsTrait::flush_report].
+    fn flush_report(&self) -> Result<(), DebugMetricsError>;
+
+    /// See [DebugMetricsTrait::write_textfile].
+    fn write_textfile(&self, path: &Path) -> Result<(), DebugMetricsError>;
 
     fn with_drop_hook<CallFn>(&self, call_fn: CallFn) -> DropHookSafe<Self, CallFn>
     where
@@ -48,10 +240,131 @@ impl<DM: DebugMetricsTrait> DebugMetricsSafe<DM> {
     }
 }
 
+impl<DM: DebugMetricsTrait + Send + 'static> DebugMetricsSafe<DM> {
+    /// Dump the metrics whenever a thread panics, before running the previously installed hook,
+    /// until the returned guard is dropped.
+    ///
+    /// The hook does not keep the metrics alive. If the metrics are locked when the panic happens,
+    /// e.g. because the panic happened inside a drop hook, nothing is dumped.
+    pub fn install_panic_hook(&self, target: DumpTarget) -> PanicHookGuard {
+        let inner = Arc::downgrade(&self.inner);
+        let previous = Arc::new(Mutex::new(Some(std::panic::take_hook())));
+        let hook_previous = previous.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            if let Some(inner) = inner.upgrade() {
+                let lock = match inner.try_lock() {
+                    Ok(lock) => Some(lock),
+                    Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+                    Err(TryLockError::WouldBlock) => None,
+                };
+                match lock {
+                    Some(mut lock) => {
+                        if let Err(e) = lock.dump(target) {
+                            eprintln!("Unable to dump debug metrics: {e}");
+                        }
+                    }
+                    None => eprintln!("Debug metrics are locked, not dumping them"),
+                }
+            }
+            let previous = hook_previous
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(previous) = previous.as_ref() {
+                previous(info);
+            }
+        }));
+        PanicHookGuard { previous }
+    }
+
+    /// Write the metrics to `path` in the Prometheus text exposition format every `interval` on a
+    /// background thread, see [DebugMetricsTrait::write_textfile].
+    ///
+    /// The thread does not keep the metrics alive, and stops once they are dropped.
+    pub fn spawn_textfile_writer<P: Into<PathBuf>>(
+        &self,
+        path: P,
+        interval: Duration,
+    ) -> TextfileWriter {
+        let path = path.into();
+        let inner = Arc::downgrade(&self.inner);
+        let (stop, stopped) = channel::<()>();
+        let thread = std::thread::spawn(move || loop {
+            let last = !matches!(
+                stopped.recv_timeout(interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            let lock = inner
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Err(e) = lock.write_textfile(&path) {
+                log::error!("Unable to write debug metrics to {}: {e}", path.display());
+            }
+            if last {
+                return;
+            }
+        });
+        TextfileWriter {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Flush the events written by [crate::OutputMode::Streaming] every `interval` on a background
+    /// thread, see [DebugMetricsTrait::flush_stream], so that they reach the writer even when the
+    /// process hangs between events.
+    ///
+    /// The thread does not keep the metrics alive, and stops once they are dropped.
+    pub fn spawn_stream_flusher(&self, interval: Duration) -> StreamFlusher {
+        let inner = Arc::downgrade(&self.inner);
+        let (stop, stopped) = channel::<()>();
+        let thread = std::thread::spawn(move || loop {
+            let last = !matches!(
+                stopped.recv_timeout(interval),
+                Err(RecvTimeoutError::Timeout)
+            );
+            let Some(inner) = inner.upgrade() else {
+                return;
+            };
+            inner
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .flush_stream();
+            if last {
+                return;
+            }
+        });
+        StreamFlusher {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+
 impl<DM: DebugMetricsTrait> DebugMetricsSafeTrait for DebugMetricsSafe<DM> {
-    fn add_recording_rule<Key: Into<String>>(&self, metric: Key, additional: &[&'static str]) {
+    fn try_add_recording_rule<Key: Into<String>>(
+        &self,
+        metric: Key,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.try_add_recording_rule(metric, additional)
+    }
+
+    fn try_add_pattern_recording_rule(
+        &self,
+        trigger: KeyPattern,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.try_add_pattern_recording_rule(trigger, additional)
+    }
+
+    fn try_add_rule(&self, rule: RecordingRuleBuilder) -> Result<(), DebugMetricsError> {
         let mut lock = self.inner.lock().unwrap();
-        lock.add_recording_rule(metric, additional);
+        lock.try_add_rule(rule)
     }
 
     fn add_drop_hook<Key: Into<String>>(&self, key: Key) {
@@ -59,23 +372,145 @@ impl<DM: DebugMetricsTrait> DebugMetricsSafeTrait for DebugMetricsSafe<DM> {
         lock.add_drop_hook(key);
     }
 
+    #[track_caller]
     fn inc<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter) {
         let mut lock = self.inner.lock().unwrap();
         lock.inc(key, labels);
     }
 
+    #[track_caller]
+    fn dec<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.dec(key, labels);
+    }
+
+    #[track_caller]
+    fn add<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.add(key, n, labels);
+    }
+
+    #[track_caller]
+    fn sub<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.sub(key, n, labels);
+    }
+
+    #[track_caller]
     fn set<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter) {
         let mut lock = self.inner.lock().unwrap();
         lock.set(key, value, labels);
     }
 
+    #[track_caller]
+    fn set_gauge<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: i64, labels: Iter) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_gauge(key, value, labels);
+    }
+
+    #[track_caller]
+    fn record<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.record(key, value, labels);
+    }
+
+    fn record_duration<Key: Into<String>, Iter: LabelIter>(
+        &self,
+        key: Key,
+        duration: Duration,
+        panicked: bool,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.record_duration(key, duration, panicked, labels, location);
+    }
+
+    #[track_caller]
     fn set_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value) {
         let mut lock = self.inner.lock().unwrap();
         lock.set_label(key, value);
     }
 
-    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<EventType> {
+    #[track_caller]
+    fn set_thread_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.set_thread_label(key, value);
+    }
+
+    #[track_caller]
+    fn remove_label<Key: Into<String>>(&self, key: Key) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.remove_label(key);
+    }
+
+    fn push_label(
+        &self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) -> Option<String> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.push_label(key, value, location)
+    }
+
+    fn pop_label(
+        &self,
+        key: String,
+        previous: Option<String>,
+        location: &'static Location<'static>,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.pop_label(key, previous, location);
+    }
+
+    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<Event> {
         let lock = self.inner.lock().unwrap();
         lock.events_for_key(key)
     }
+
+    fn series<Key: Into<String>>(&self, metric: Key) -> Series {
+        let lock = self.inner.lock().unwrap();
+        lock.series(metric)
+    }
+
+    fn call_sites(&self) -> CallSites {
+        let lock = self.inner.lock().unwrap();
+        lock.call_sites()
+    }
+
+    fn elapsed(&self) -> Duration {
+        let lock = self.inner.lock().unwrap();
+        lock.elapsed()
+    }
+
+    fn open_span(&self, name: String) -> usize {
+        let mut lock = self.inner.lock().unwrap();
+        lock.open_span(name)
+    }
+
+    fn close_span(&self, depth: usize) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.close_span(depth);
+    }
+
+    fn closed_spans(&self) -> Vec<SpanRecord> {
+        let lock = self.inner.lock().unwrap();
+        lock.closed_spans()
+    }
+
+    fn folded_stacks(&self, weight: StackWeight) -> BTreeMap<String, u64> {
+        let lock = self.inner.lock().unwrap();
+        lock.folded_stacks(weight)
+    }
+
+    fn write_textfile(&self, path: &Path) -> Result<(), DebugMetricsError> {
+        let lock = self.inner.lock().unwrap();
+        lock.write_textfile(path)
+    }
+
+    fn flush_report(&self) -> Result<(), DebugMetricsError> {
+        let mut lock = self.inner.lock().unwrap();
+        lock.flush_report()
+    }
 }
@@ -0,0 +1,540 @@
+use crate::clock::Clock;
+use crate::debug_metrics::{CallSites, DebugMetrics, DebugMetricsTrait, Event, KeyPattern};
+#[cfg(debug_metrics_enabled)]
+use crate::debug_metrics::{MetricUpdate, SampleValue, ThreadInfo};
+use crate::debug_metrics_safe::DebugMetricsSafeTrait;
+use crate::error::DebugMetricsError;
+use crate::label_iter::LabelIter;
+use crate::rule::RecordingRuleBuilder;
+use crate::series::Series;
+use crate::span::{SpanRecord, StackWeight};
+#[cfg(debug_metrics_enabled)]
+use crate::thread_exit::{notify_on_exit, ExitedThreads};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::panic::Location;
+use std::path::Path;
+#[cfg(debug_metrics_enabled)]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(debug_metrics_enabled)]
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+#[cfg(debug_metrics_enabled)]
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Operations a shard buffers before a replay is requested from the background thread.
+#[cfg(debug_metrics_enabled)]
+const REPLAY_THRESHOLD: usize = 4096;
+
+/// Operations a shard buffers before the thread buffering them replays them itself, when the
+/// background thread cannot keep up.
+#[cfg(debug_metrics_enabled)]
+const REPLAY_LIMIT: usize = 4 * REPLAY_THRESHOLD;
+
+/// A thread safe collector for contended workloads, with the same API as [crate::DebugMetricsSafe].
+///
+/// Instead of locking the collector for every change, changes are buffered in shards, one per
+/// thread while there are fewer threads than shards, and replayed into the collector in the order
+/// they happened when it is queried, when the last clone is dropped, and by a background thread
+/// when a shard fills up. Every change keeps the time, thread and call site it happened at.
+///
+/// Changes are ordered by the time the [Clock] gives them, so threads do not share anything but
+/// the clock while buffering. Changes in different shards at the same time are replayed in the
+/// order of their shards rather than the order they happened in. A [crate::ManualClock] that is
+/// not advanced gives every change the same time, so it needs to be advanced between changes on
+/// different threads whose order matters.
+///
+/// Recording rules, drop hooks, spans and scoped labels are applied straight away, after the
+/// buffered changes.
+pub struct DebugMetricsSharded<W: Write + Send + 'static> {
+    /// Dropped first, so that the collector is no longer being replayed into once the last clone
+    /// drops it
+    #[cfg(debug_metrics_enabled)]
+    replayer: Arc<Replayer>,
+    inner: Arc<ShardedInner<W>>,
+}
+
+// Derive does not work, because it expects the generic to be Clone as well
+impl<W: Write + Send + 'static> Clone for DebugMetricsSharded<W> {
+    fn clone(&self) -> Self {
+        DebugMetricsSharded {
+            #[cfg(debug_metrics_enabled)]
+            replayer: self.replayer.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct ShardedInner<W: Write + Send + 'static> {
+    /// Each in the order the operations happened
+    #[cfg(debug_metrics_enabled)]
+    shards: Box<[Shard]>,
+    /// Set while a replay is requested from the [Replayer], so that it is only requested once
+    #[cfg(debug_metrics_enabled)]
+    replay_requested: AtomicBool,
+    clock: Arc<dyn Clock>,
+    /// Buffering is skipped when the collector would ignore the changes anyway
+    #[cfg(debug_metrics_enabled)]
+    collecting: bool,
+    /// Of the collector, shared so that threads can be registered when they buffer thread labels
+    #[cfg(debug_metrics_enabled)]
+    exited_threads: ExitedThreads,
+    collector: Mutex<DebugMetrics<W>>,
+}
+
+/// The operations buffered by the threads of a shard.
+///
+/// Aligned to its own cache lines, so that threads buffering in neighbouring shards do not take
+/// the cache line of each other's lock.
+#[cfg(debug_metrics_enabled)]
+#[repr(align(128))]
+struct Shard(Mutex<Vec<BufferedOp>>);
+
+/// A change to the collector, as it happened.
+#[cfg(debug_metrics_enabled)]
+pub(crate) struct BufferedOp {
+    pub(crate) elapsed: Duration,
+    pub(crate) thread: ThreadInfo,
+    pub(crate) location: &'static Location<'static>,
+    pub(crate) kind: OpKind,
+}
+
+#[cfg(debug_metrics_enabled)]
+pub(crate) enum OpKind {
+    Metric {
+        key: String,
+        update: MetricUpdate,
+        labels: Vec<(String, String)>,
+    },
+    Label {
+        key: String,
+        value: String,
+    },
+    ThreadLabel {
+        key: String,
+        value: String,
+    },
+    RemoveLabel {
+        key: String,
+    },
+    Sample {
+        key: String,
+        sample: SampleValue,
+        labels: Vec<(String, String)>,
+    },
+}
+
+impl<W: Write + Send + 'static> DebugMetricsSharded<W> {
+    /// Shard by the available parallelism.
+    pub fn new(debug_metrics: DebugMetrics<W>) -> Self {
+        let shards = std::thread::available_parallelism().map_or(1, usize::from);
+        DebugMetricsSharded::with_shards(debug_metrics, shards)
+    }
+
+    /// Nothing is buffered when metrics are compiled out, so `shards` is ignored then.
+    pub fn with_shards(debug_metrics: DebugMetrics<W>, shards: usize) -> Self {
+        #[cfg(debug_metrics_enabled)]
+        let collecting = debug_metrics.is_collecting();
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = shards;
+        let inner = Arc::new(ShardedInner {
+            #[cfg(debug_metrics_enabled)]
+            shards: (0..shards.max(1))
+                .map(|_| Shard(Mutex::new(Vec::new())))
+                .collect(),
+            #[cfg(debug_metrics_enabled)]
+            replay_requested: AtomicBool::new(false),
+            clock: debug_metrics.clock(),
+            #[cfg(debug_metrics_enabled)]
+            collecting,
+            #[cfg(debug_metrics_enabled)]
+            exited_threads: debug_metrics.exited_threads(),
+            collector: Mutex::new(debug_metrics),
+        });
+        DebugMetricsSharded {
+            #[cfg(debug_metrics_enabled)]
+            replayer: Arc::new(if collecting {
+                Replayer::spawn(inner.clone())
+            } else {
+                Replayer::default()
+            }),
+            inner,
+        }
+    }
+
+    #[cfg(debug_metrics_enabled)]
+    #[track_caller]
+    fn buffer(&self, kind: OpKind) {
+        self.buffer_at(kind, Location::caller());
+    }
+
+    #[cfg(debug_metrics_enabled)]
+    fn buffer_at(&self, kind: OpKind, location: &'static Location<'static>) {
+        if !self.inner.collecting {
+            return;
+        }
+        let (shard, thread) = current_thread();
+        let buffered = {
+            let mut shard = lock(&self.inner.shards[shard % self.inner.shards.len()].0);
+            // Timed while the shard is locked, so that ops buffered after a replay are never
+            // earlier than the ops it replayed
+            shard.push(BufferedOp {
+                elapsed: self.inner.clock.elapsed(),
+                thread,
+                location,
+                kind,
+            });
+            shard.len()
+        };
+        if buffered >= REPLAY_LIMIT {
+            drop(self.replayed());
+        } else if buffered >= REPLAY_THRESHOLD
+            && !self.inner.replay_requested.swap(true, Ordering::Relaxed)
+        {
+            self.replayer.request();
+        }
+    }
+
+    /// Replay the buffered operations, and return the up to date collector.
+    fn replayed(&self) -> MutexGuard<'_, DebugMetrics<W>> {
+        self.inner.replay()
+    }
+}
+
+impl<W: Write + Send + 'static> ShardedInner<W> {
+    #[cfg(debug_metrics_enabled)]
+    fn replay(&self) -> MutexGuard<'_, DebugMetrics<W>> {
+        let mut collector = lock(&self.collector);
+        // Taken before the shards are drained, so that every op these threads buffered is replayed
+        // before their thread labels are forgotten
+        let exited = collector.take_exited_threads();
+        // Every shard is locked before any is taken, so no op can be buffered in a shard that
+        // was already taken at an earlier time than an op that is being replayed. The buffers are
+        // swapped out rather than copied, so the shards are only locked briefly.
+        let mut shards: Vec<_> = self.shards.iter().map(|shard| lock(&shard.0)).collect();
+        let taken: Vec<Vec<BufferedOp>> = shards
+            .iter_mut()
+            .map(|s| std::mem::take(&mut **s))
+            .collect();
+        drop(shards);
+        let mut ops: Vec<BufferedOp> = taken.into_iter().flatten().collect();
+        // Stable, so that the ops of each shard stay in order
+        ops.sort_by_key(|op| op.elapsed);
+        for op in ops {
+            collector.replay(op);
+        }
+        collector.forget_threads(exited);
+        collector
+    }
+
+    #[cfg(not(debug_metrics_enabled))]
+    fn replay(&self) -> MutexGuard<'_, DebugMetrics<W>> {
+        lock(&self.collector)
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for ShardedInner<W> {
+    fn drop(&mut self) {
+        // The collector writes its report when it is dropped after this
+        drop(self.replay());
+    }
+}
+
+/// Replays the shards on a background thread whenever a shard fills up, so that the thread filling
+/// it does not stall on the replay, and neither do the other threads, which only wait while the
+/// shards are swapped out.
+///
+/// The thread is stopped when the last clone of the [DebugMetricsSharded] drops this.
+#[cfg(debug_metrics_enabled)]
+#[derive(Default)]
+struct Replayer {
+    requests: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(debug_metrics_enabled)]
+impl Replayer {
+    fn spawn<W: Write + Send + 'static>(inner: Arc<ShardedInner<W>>) -> Self {
+        let (requests, requested) = channel::<()>();
+        let thread = std::thread::spawn(move || {
+            while requested.recv().is_ok() {
+                // Cleared first, so that a shard filling up during the replay requests another one
+                inner.replay_requested.store(false, Ordering::Relaxed);
+                drop(inner.replay());
+            }
+        });
+        Replayer {
+            requests: Some(requests),
+            thread: Some(thread),
+        }
+    }
+
+    fn request(&self) {
+        if let Some(requests) = &self.requests {
+            // Only fails when the thread panicked, in which case the shard is replayed once it
+            // reaches the limit
+            let _ = requests.send(());
+        }
+    }
+}
+
+#[cfg(debug_metrics_enabled)]
+impl Drop for Replayer {
+    fn drop(&mut self) {
+        // Dropping the sender stops the thread once it finished replaying
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The shard of this thread, and the thread as it is buffered, which is looked up once per thread.
+///
+/// Threads are given shards in turn, so that threads do not share a shard while there are enough.
+#[cfg(debug_metrics_enabled)]
+fn current_thread() -> (usize, ThreadInfo) {
+    static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+    thread_local! {
+        static THREAD: (usize, ThreadInfo) =
+            (NEXT_SHARD.fetch_add(1, Ordering::Relaxed), ThreadInfo::current());
+    }
+    THREAD.with(|(shard, thread)| (*shard, thread.clone()))
+}
+
+#[cfg(debug_metrics_enabled)]
+fn collect_labels<Iter: LabelIter>(labels: Iter) -> Vec<(String, String)> {
+    labels
+        .iter()
+        .map(|(k, v)| (k.as_ref().to_string(), v.as_ref().to_string()))
+        .collect()
+}
+
+impl<W: Write + Send + 'static> DebugMetricsSafeTrait for DebugMetricsSharded<W> {
+    fn try_add_recording_rule<Key: Into<String>>(
+        &self,
+        metric: Key,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        self.replayed().try_add_recording_rule(metric, additional)
+    }
+
+    fn try_add_pattern_recording_rule(
+        &self,
+        trigger: KeyPattern,
+        additional: &[&'static str],
+    ) -> Result<(), DebugMetricsError> {
+        self.replayed()
+            .try_add_pattern_recording_rule(trigger, additional)
+    }
+
+    fn try_add_rule(&self, rule: RecordingRuleBuilder) -> Result<(), DebugMetricsError> {
+        self.replayed().try_add_rule(rule)
+    }
+
+    fn add_drop_hook<Key: Into<String>>(&self, key: Key) {
+        self.replayed().add_drop_hook(key);
+    }
+
+    #[track_caller]
+    fn inc<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::Add(1),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, labels);
+    }
+
+    #[track_caller]
+    fn dec<Key: Into<String>, Iter: LabelIter>(&self, key: Key, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::Sub(1),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, labels);
+    }
+
+    #[track_caller]
+    fn add<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::Add(n),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, n, labels);
+    }
+
+    #[track_caller]
+    fn sub<Key: Into<String>, Iter: LabelIter>(&self, key: Key, n: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::Sub(n),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, n, labels);
+    }
+
+    #[track_caller]
+    fn set<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::Set(value),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
+    }
+
+    #[track_caller]
+    fn set_gauge<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: i64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Metric {
+            key: key.into(),
+            update: MetricUpdate::SetGauge(value),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
+    }
+
+    #[track_caller]
+    fn record<Key: Into<String>, Iter: LabelIter>(&self, key: Key, value: u64, labels: Iter) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Sample {
+            key: key.into(),
+            sample: SampleValue::Value(value),
+            labels: collect_labels(labels),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value, labels);
+    }
+
+    fn record_duration<Key: Into<String>, Iter: LabelIter>(
+        &self,
+        key: Key,
+        duration: Duration,
+        panicked: bool,
+        labels: Iter,
+        location: &'static Location<'static>,
+    ) {
+        #[cfg(debug_metrics_enabled)]
+        {
+            let kind = OpKind::Sample {
+                key: key.into(),
+                sample: SampleValue::Duration { duration, panicked },
+                labels: collect_labels(labels),
+            };
+            self.buffer_at(kind, location);
+        }
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, duration, panicked, labels, location);
+    }
+
+    #[track_caller]
+    fn set_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::Label {
+            key: key.into(),
+            value: value.into(),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value);
+    }
+
+    #[track_caller]
+    fn set_thread_label<Key: Into<String>, Value: Into<String>>(&self, key: Key, value: Value) {
+        #[cfg(debug_metrics_enabled)]
+        if self.inner.collecting {
+            notify_on_exit(&self.inner.exited_threads);
+        }
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::ThreadLabel {
+            key: key.into(),
+            value: value.into(),
+        });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = (key, value);
+    }
+
+    #[track_caller]
+    fn remove_label<Key: Into<String>>(&self, key: Key) {
+        #[cfg(debug_metrics_enabled)]
+        self.buffer(OpKind::RemoveLabel { key: key.into() });
+        #[cfg(not(debug_metrics_enabled))]
+        let _ = key;
+    }
+
+    fn push_label(
+        &self,
+        key: String,
+        value: String,
+        location: &'static Location<'static>,
+    ) -> Option<String> {
+        self.replayed().push_label(key, value, location)
+    }
+
+    fn pop_label(
+        &self,
+        key: String,
+        previous: Option<String>,
+        location: &'static Location<'static>,
+    ) {
+        self.replayed().pop_label(key, previous, location);
+    }
+
+    fn events_for_key<Key: Into<String>>(&self, key: Key) -> Vec<Event> {
+        self.replayed().events_for_key(key)
+    }
+
+    fn series<Key: Into<String>>(&self, metric: Key) -> Series {
+        self.replayed().series(metric)
+    }
+
+    fn call_sites(&self) -> CallSites {
+        self.replayed().call_sites()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.inner.clock.elapsed()
+    }
+
+    fn open_span(&self, name: String) -> usize {
+        self.replayed().open_span(name)
+    }
+
+    fn close_span(&self, depth: usize) {
+        self.replayed().close_span(depth);
+    }
+
+    fn closed_spans(&self) -> Vec<SpanRecord> {
+        self.replayed().closed_spans()
+    }
+
+    fn folded_stacks(&self, weight: StackWeight) -> BTreeMap<String, u64> {
+        self.replayed().folded_stacks(weight)
+    }
+
+    fn flush_report(&self) -> Result<(), DebugMetricsError> {
+        self.replayed().flush_report()
+    }
+
+    fn write_textfile(&self, path: &Path) -> Result<(), DebugMetricsError> {
+        self.replayed().write_textfile(path)
+    }
+}
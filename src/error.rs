@@ -0,0 +1,59 @@
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum DebugMetricsError {
+    /// A recording rule pattern is not a valid regex
+    InvalidRecordingRule {
+        metric: String,
+        pattern: &'static str,
+        source: regex::Error,
+    },
+    /// The trigger of a pattern recording rule is not a valid regex
+    InvalidTrigger {
+        pattern: &'static str,
+        source: regex::Error,
+    },
+    /// Writing to the output writer failed
+    Writer(std::io::Error),
+    /// [crate::init_global] was called after the global collector was already in use
+    GlobalAlreadyInitialised,
+}
+
+impl Display for DebugMetricsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DebugMetricsError::InvalidRecordingRule {
+                metric,
+                pattern,
+                source,
+            } => write!(
+                f,
+                "invalid pattern {pattern:?} in recording rule for {metric:?}: {source}"
+            ),
+            DebugMetricsError::InvalidTrigger { pattern, source } => {
+                write!(f, "invalid recording rule trigger {pattern:?}: {source}")
+            }
+            DebugMetricsError::Writer(e) => write!(f, "failed to write debug metrics: {e}"),
+            DebugMetricsError::GlobalAlreadyInitialised => {
+                write!(f, "the global debug metrics are already initialised")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DebugMetricsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DebugMetricsError::InvalidRecordingRule { source, .. }
+            | DebugMetricsError::InvalidTrigger { source, .. } => Some(source),
+            DebugMetricsError::Writer(e) => Some(e),
+            DebugMetricsError::GlobalAlreadyInitialised => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DebugMetricsError {
+    fn from(e: std::io::Error) -> Self {
+        DebugMetricsError::Writer(e)
+    }
+}
@@ -0,0 +1,157 @@
+//! A process wide collector, for code where passing metrics around is not worth it.
+//!
+//! Use it through the [crate::dm_inc], [crate::dm_set] and [crate::dm_label] macros, which compile
+//! to nothing when metrics are not collected. Statics are never dropped, so keep a
+//! [GlobalFlushGuard] alive in `main` to get the report.
+use crate::config::DebugMetricsConfig;
+use crate::debug_metrics::DebugMetrics;
+use crate::debug_metrics_safe::{DebugMetricsSafe, DebugMetricsSafeTrait};
+use crate::error::DebugMetricsError;
+use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+pub type GlobalDebugMetrics = DebugMetricsSafe<DebugMetrics<Box<dyn Write + Send>>>;
+
+static GLOBAL: OnceLock<GlobalDebugMetrics> = OnceLock::new();
+
+/// Whether a [GlobalFlushGuard] was ever created, to warn when the report would never be written.
+static FLUSH_GUARD_CREATED: AtomicBool = AtomicBool::new(false);
+
+/// Set up the global collector with a writer and config.
+///
+/// Must be called before the global collector is first used, otherwise it has already been set up
+/// with stdout and the default config. As statics are never dropped, the report is written when
+/// the returned guard is dropped, so keep it alive in `main`.
+pub fn init_global<W: Write + Send + 'static>(
+    writer: W,
+    config: DebugMetricsConfig,
+) -> Result<GlobalFlushGuard, DebugMetricsError> {
+    let writer: Box<dyn Write + Send> = Box::new(writer);
+    GLOBAL
+        .set(DebugMetrics::new(writer, config).safe())
+        .map_err(|_| DebugMetricsError::GlobalAlreadyInitialised)?;
+    Ok(GlobalFlushGuard::new())
+}
+
+/// The global collector, set up with stdout and the default config unless [init_global] was called
+/// first.
+///
+/// When it is set up here without a [GlobalFlushGuard] having been created, a warning is printed to
+/// stderr, as its report would never be written.
+pub fn global() -> &'static GlobalDebugMetrics {
+    GLOBAL.get_or_init(|| {
+        if !FLUSH_GUARD_CREATED.load(Ordering::Relaxed) {
+            eprintln!(
+                "Debug metrics: the global collector was set up on first use without a \
+                 GlobalFlushGuard, so its report is never written unless flush_global is called"
+            );
+        }
+        let writer: Box<dyn Write + Send> = Box::new(stdout());
+        DebugMetrics::new(writer, DebugMetricsConfig::default()).safe()
+    })
+}
+
+/// Write the report of the global collector.
+///
+/// The report is only written once, see [crate::DebugMetricsTrait::flush_report].
+pub fn flush_global() -> Result<(), DebugMetricsError> {
+    match GLOBAL.get() {
+        Some(global) => global.flush_report(),
+        None => Ok(()),
+    }
+}
+
+/// Writes the report of the global collector when dropped, in place of an `atexit` handler.
+#[must_use = "the report is written when the guard is dropped"]
+pub struct GlobalFlushGuard {
+    _private: (),
+}
+
+impl GlobalFlushGuard {
+    /// For when the global collector is used without [init_global].
+    pub fn new() -> Self {
+        FLUSH_GUARD_CREATED.store(true, Ordering::Relaxed);
+        GlobalFlushGuard { _private: () }
+    }
+}
+
+impl Default for GlobalFlushGuard {
+    fn default() -> Self {
+        GlobalFlushGuard::new()
+    }
+}
+
+impl Drop for GlobalFlushGuard {
+    fn drop(&mut self) {
+        if let Err(e) = flush_global() {
+            log::error!("Unable to write global debug metrics report: {e}");
+        }
+    }
+}
+
+/// Increment a metric of the global collector, with optional `label => value` pairs.
+///
+/// `dm_inc!("requests")` or `dm_inc!("requests", "route" => "/a")`
+#[cfg(debug_metrics_enabled)]
+#[macro_export]
+macro_rules! dm_inc {
+    ($key:expr $(,)?) => {
+        $crate::DebugMetricsSafeTrait::inc($crate::global(), $key, $crate::NoLabels)
+    };
+    ($key:expr, $($label:expr => $value:expr),+ $(,)?) => {
+        $crate::DebugMetricsSafeTrait::inc(
+            $crate::global(),
+            $key,
+            [$(($label, $value)),+].into_iter(),
+        )
+    };
+}
+
+/// Set a metric of the global collector, with optional `label => value` pairs.
+///
+/// `dm_set!("queue_depth", 3)` or `dm_set!("queue_depth", 3, "queue" => "io")`
+#[cfg(debug_metrics_enabled)]
+#[macro_export]
+macro_rules! dm_set {
+    ($key:expr, $count:expr $(,)?) => {
+        $crate::DebugMetricsSafeTrait::set($crate::global(), $key, $count, $crate::NoLabels)
+    };
+    ($key:expr, $count:expr, $($label:expr => $value:expr),+ $(,)?) => {
+        $crate::DebugMetricsSafeTrait::set(
+            $crate::global(),
+            $key,
+            $count,
+            [$(($label, $value)),+].into_iter(),
+        )
+    };
+}
+
+/// Set a label of the global collector.
+///
+/// `dm_label!("stage", "compaction")`
+#[cfg(debug_metrics_enabled)]
+#[macro_export]
+macro_rules! dm_label {
+    ($key:expr, $value:expr $(,)?) => {
+        $crate::DebugMetricsSafeTrait::set_label($crate::global(), $key, $value)
+    };
+}
+
+#[cfg(not(debug_metrics_enabled))]
+#[macro_export]
+macro_rules! dm_inc {
+    ($($tt:tt)*) => {};
+}
+
+#[cfg(not(debug_metrics_enabled))]
+#[macro_export]
+macro_rules! dm_set {
+    ($($tt:tt)*) => {};
+}
+
+#[cfg(not(debug_metrics_enabled))]
+#[macro_export]
+macro_rules! dm_label {
+    ($($tt:tt)*) => {};
+}
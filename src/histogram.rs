@@ -0,0 +1,118 @@
+use crate::config::HistogramBuckets;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// The distribution of the values recorded for a key, see [crate::DebugMetricsTrait::record].
+///
+/// Count, sum, min and max are exact. Percentiles are the upper bound of the bucket they fall in,
+/// clamped to the min and max, so their precision depends on the [HistogramBuckets].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    buckets_config: HistogramBuckets,
+    /// Count of values per bucket, keyed by the inclusive upper bound of the bucket
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: HistogramBuckets) -> Self {
+        Histogram {
+            buckets_config: buckets,
+            buckets: BTreeMap::new(),
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let upper_bound = match self.buckets_config {
+            HistogramBuckets::LogLinear { sub_bucket_bits } => {
+                log_linear_upper_bound(value, sub_bucket_bits)
+            }
+            HistogramBuckets::Explicit(bounds) => {
+                let i = bounds.partition_point(|bound| *bound < value);
+                bounds.get(i).copied().unwrap_or(u64::MAX)
+            }
+        };
+        *self.buckets.entry(upper_bound).or_default() += 1;
+        self.count += 1;
+        self.sum += value as u128;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> u128 {
+        self.sum
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// The value below which `percentile` percent of the recorded values fall, e.g. 99.0 for p99.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0) * self.count as f64).ceil() as u64;
+        let rank = rank.clamp(1, self.count);
+        let mut seen = 0;
+        for (upper_bound, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Some((*upper_bound).clamp(self.min, self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Count of values per bucket, keyed by the inclusive upper bound of the bucket.
+    pub fn buckets(&self) -> &BTreeMap<u64, u64> {
+        &self.buckets
+    }
+}
+
+/// Values below `2^sub_bucket_bits` get a bucket each, larger values share a bucket with the
+/// values that only differ from them below their `sub_bucket_bits + 1` most significant bits.
+fn log_linear_upper_bound(value: u64, sub_bucket_bits: u8) -> u64 {
+    let sub_bucket_bits = u32::from(sub_bucket_bits.min(63));
+    if value < 1 << sub_bucket_bits {
+        return value;
+    }
+    let magnitude = u64::BITS - 1 - value.leading_zeros();
+    let shift = magnitude - sub_bucket_bits;
+    value | ((1 << shift) - 1)
+}
+
+impl Display for Histogram {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (Some(min), Some(max), Some(mean)) = (self.min(), self.max(), self.mean()) else {
+            return write!(f, "count=0");
+        };
+        write!(
+            f,
+            "count={} min={min} max={max} mean={mean:.2} p50={} p90={} p99={}",
+            self.count,
+            self.percentile(50.0).unwrap_or_default(),
+            self.percentile(90.0).unwrap_or_default(),
+            self.percentile(99.0).unwrap_or_default(),
+        )
+    }
+}
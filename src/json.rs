@@ -0,0 +1,72 @@
+//! Just enough JSON writing for the reports, without pulling in a serialisation framework.
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::io::Write;
+
+/// Write `s` as a quoted JSON string.
+pub(crate) fn write_str<W: Write + ?Sized>(writer: &mut W, s: &str) -> std::io::Result<()> {
+    writer.write_all(b"\"")?;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let escaped = match c {
+            '"' => "\\\"",
+            '\\' => "\\\\",
+            '\n' => "\\n",
+            '\r' => "\\r",
+            '\t' => "\\t",
+            c if (c as u32) < 0x20 => "",
+            _ => continue,
+        };
+        writer.write_all(&s.as_bytes()[start..i])?;
+        if escaped.is_empty() {
+            write!(writer, "\\u{:04x}", c as u32)?;
+        } else {
+            writer.write_all(escaped.as_bytes())?;
+        }
+        start = i + c.len_utf8();
+    }
+    writer.write_all(&s.as_bytes()[start..])?;
+    writer.write_all(b"\"")
+}
+
+/// Write a map with string keys as a JSON object, using `write_value` for the values.
+pub(crate) fn write_object<W, V, F>(
+    writer: &mut W,
+    map: &BTreeMap<String, V>,
+    mut write_value: F,
+) -> std::io::Result<()>
+where
+    W: Write + ?Sized,
+    F: FnMut(&mut W, &V) -> std::io::Result<()>,
+{
+    writer.write_all(b"{")?;
+    for (i, (k, v)) in map.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write_str(writer, k)?;
+        writer.write_all(b":")?;
+        write_value(writer, v)?;
+    }
+    writer.write_all(b"}")
+}
+
+/// Write a map of numbers as a JSON object.
+pub(crate) fn write_number_object<W, V>(
+    writer: &mut W,
+    map: &BTreeMap<String, V>,
+) -> std::io::Result<()>
+where
+    W: Write + ?Sized,
+    V: Display,
+{
+    write_object(writer, map, |w, v| write!(w, "{v}"))
+}
+
+/// Write a map of strings as a JSON object.
+pub(crate) fn write_string_object<W: Write + ?Sized>(
+    writer: &mut W,
+    map: &BTreeMap<String, String>,
+) -> std::io::Result<()> {
+    write_object(writer, map, |w, v| write_str(w, v))
+}
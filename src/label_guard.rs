@@ -0,0 +1,39 @@
+use crate::debug_metrics::DebugMetricsTrait;
+use std::panic::Location;
+
+/// Restores a label when dropped, see [DebugMetricsTrait::scoped_label].
+///
+/// Guards are expected to be dropped in the reverse order they were created, like scopes. A guard
+/// restores the value the label had when it was created, whatever the label was set to since.
+pub struct LabelGuard<'a, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    pub(crate) debug_metrics: &'a mut DM,
+    pub(crate) key: String,
+    pub(crate) previous: Option<String>,
+    pub(crate) location: &'static Location<'static>,
+}
+
+impl<DM> LabelGuard<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    /// The collector, for recording metrics while the label is set.
+    pub fn debug_metrics(&mut self) -> &mut DM {
+        self.debug_metrics
+    }
+}
+
+impl<DM> Drop for LabelGuard<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    fn drop(&mut self) {
+        self.debug_metrics.pop_label(
+            std::mem::take(&mut self.key),
+            self.previous.take(),
+            self.location,
+        );
+    }
+}
@@ -0,0 +1,30 @@
+use crate::debug_metrics_safe::DebugMetricsSafeTrait;
+use std::panic::Location;
+
+/// Restores a label when dropped, see [DebugMetricsSafeTrait::scoped_label].
+///
+/// Holds a clone of the collector, so the collector can still be used while the label is set.
+/// Labels are shared by all threads, so prefer [DebugMetricsSafeTrait::set_thread_label] for
+/// context that differs between threads.
+pub struct LabelGuardSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    pub(crate) debug_metrics: DM,
+    pub(crate) key: String,
+    pub(crate) previous: Option<String>,
+    pub(crate) location: &'static Location<'static>,
+}
+
+impl<DM> Drop for LabelGuardSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    fn drop(&mut self) {
+        self.debug_metrics.pop_label(
+            std::mem::take(&mut self.key),
+            self.previous.take(),
+            self.location,
+        );
+    }
+}
@@ -1,17 +1,86 @@
+mod clock;
 mod config;
 mod debug_metrics;
 mod debug_metrics_safe;
+mod debug_metrics_sharded;
 mod drop_hook;
 mod drop_hook_safe;
+mod error;
+mod global;
+mod histogram;
+mod json;
+mod label_guard;
+mod label_guard_safe;
 mod label_iter;
-#[cfg(test)]
+mod panic_hook;
+mod prometheus;
+mod reporter;
+mod rule;
+mod series;
+mod span;
+mod span_safe;
+mod stream_flusher;
+// Most tests expect metrics to be collected
+#[cfg(all(test, debug_metrics_enabled))]
 mod test;
+#[cfg(test)]
+mod test_features;
+#[cfg(debug_metrics_enabled)]
+mod thread_exit;
+mod timer;
+mod timer_safe;
 
+pub use clock::Clock;
+pub use clock::ManualClock;
+pub use clock::MonotonicClock;
 pub use config::DebugMetricsConfig;
+pub use config::FlushPolicy;
+pub use config::HistogramBuckets;
+pub use config::OutputFormat;
+pub use config::OutputMode;
+pub use config::RetentionPolicy;
+pub use debug_metrics::CallSites;
 pub use debug_metrics::DebugMetrics;
 pub use debug_metrics::DebugMetricsTrait;
 pub use debug_metrics::DefaultExt;
+pub use debug_metrics::DumpTarget;
+pub use debug_metrics::Event;
+pub use debug_metrics::EventType;
+pub use debug_metrics::KeyPattern;
 pub use debug_metrics_safe::DebugMetricsSafe;
 pub use debug_metrics_safe::DebugMetricsSafeTrait;
+pub use debug_metrics_sharded::DebugMetricsSharded;
+pub use error::DebugMetricsError;
+pub use global::flush_global;
+pub use global::global;
+pub use global::init_global;
+pub use global::GlobalDebugMetrics;
+pub use global::GlobalFlushGuard;
+pub use histogram::Histogram;
+pub use label_guard::LabelGuard;
+pub use label_guard_safe::LabelGuardSafe;
 pub use label_iter::LabelIter;
 pub use label_iter::NoLabels;
+pub use panic_hook::PanicHookGuard;
+pub use prometheus::PrometheusReporter;
+pub use prometheus::TextfileWriter;
+pub use reporter::ChromeTraceReporter;
+pub use reporter::DotReporter;
+pub use reporter::JsonLinesReporter;
+pub use reporter::Reporter;
+pub use reporter::Summary;
+pub use reporter::TextReporter;
+pub use rule::Comparison;
+pub use rule::Condition;
+pub use rule::RecordingRuleBuilder;
+pub use series::sum_by;
+pub use series::LabelSet;
+pub use series::Series;
+pub use span::write_folded_stacks;
+pub use span::Span;
+pub use span::SpanRecord;
+pub use span::StackWeight;
+pub use span_safe::SpanSafe;
+pub use stream_flusher::StreamFlusher;
+pub use timer::Timer;
+pub use timer_safe::TimerSafe;
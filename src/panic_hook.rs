@@ -0,0 +1,33 @@
+use std::panic::PanicHookInfo;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type PanicHook = Box<dyn Fn(&PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+/// Uninstalls the hook of [crate::DebugMetricsSafe::install_panic_hook] when dropped, by restoring
+/// the hook that was installed before it.
+///
+/// Drop guards in the reverse order they were created in, as dropping a guard replaces whatever
+/// hook is installed at that time. When dropped while panicking, the hook is left installed, as the
+/// hook cannot be changed then.
+#[must_use = "the panic hook is uninstalled when the guard is dropped"]
+pub struct PanicHookGuard {
+    /// Shared with the installed hook, which calls it after dumping
+    pub(crate) previous: Arc<Mutex<Option<PanicHook>>>,
+}
+
+impl Drop for PanicHookGuard {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        let previous = self
+            .previous
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(previous) = previous {
+            drop(std::panic::take_hook());
+            std::panic::set_hook(previous);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::debug_metrics::Event;
+use crate::reporter::{Reporter, Summary};
+use crate::series::{series_name, LabelSet};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+/// The Prometheus text exposition format, e.g. for the textfile collector of node_exporter.
+///
+/// Only the final state is written: every count as a gauge, as counts can be set and decremented,
+/// or its series if [crate::DebugMetricsConfig::series] is on, and every histogram with cumulative
+/// `_bucket`s, `_sum` and `_count`. Characters that Prometheus does not allow in names are
+/// replaced with `_`, so keys that only differ in those characters end up with the same name.
+///
+/// Counts with the same name are written as one family. A series that another count already wrote
+/// with the same name and labels, and a histogram whose names are already taken, are skipped with
+/// a warning, as the whole file would be rejected otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PrometheusReporter;
+
+impl Reporter for PrometheusReporter {
+    fn report_event(&mut self, _writer: &mut dyn Write, _event: &Event) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn report_summary(
+        &mut self,
+        writer: &mut dyn Write,
+        summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        let mut gauges: BTreeMap<String, BTreeMap<LabelSet, i64>> = BTreeMap::new();
+        for (key, count) in summary.counts {
+            let name = sanitise_name(key, true);
+            let series = match summary.series.get(key) {
+                Some(series) if !series.is_empty() => series
+                    .iter()
+                    .map(|(labels, count)| (sanitise_labels(labels), *count))
+                    .collect(),
+                _ => vec![(LabelSet::new(), *count)],
+            };
+            let family = gauges.entry(name.clone()).or_default();
+            for (labels, count) in series {
+                match family.entry(labels) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(count);
+                    }
+                    Entry::Occupied(entry) => log::warn!(
+                        "Skipping {key} with labels {:?}, as another key with the Prometheus name \
+                         {name} has the same labels",
+                        entry.key()
+                    ),
+                }
+            }
+        }
+        let mut names: BTreeSet<String> = gauges.keys().cloned().collect();
+        for (name, family) in &gauges {
+            writeln!(writer, "# TYPE {name} gauge")?;
+            for (labels, count) in family {
+                writeln!(writer, "{} {count}", series_name(name, labels))?;
+            }
+        }
+        for (key, histogram) in summary.histograms {
+            let name = sanitise_name(key, true);
+            let histogram_names = ["", "_bucket", "_sum", "_count"].map(|s| format!("{name}{s}"));
+            if histogram_names.iter().any(|n| names.contains(n)) {
+                log::warn!("Skipping histogram {key}, as its Prometheus name {name} is taken");
+                continue;
+            }
+            names.extend(histogram_names);
+            writeln!(writer, "# TYPE {name} histogram")?;
+            let mut cumulative = 0;
+            for (upper_bound, count) in histogram.buckets() {
+                cumulative += count;
+                if *upper_bound == u64::MAX {
+                    break;
+                }
+                let le = LabelSet::from([("le".to_string(), upper_bound.to_string())]);
+                let bucket = series_name(&format!("{name}_bucket"), &le);
+                writeln!(writer, "{bucket} {cumulative}")?;
+            }
+            writeln!(writer, "{name}_bucket{{le=\"+Inf\"}} {}", histogram.count())?;
+            writeln!(writer, "{name}_sum {}", histogram.sum())?;
+            writeln!(writer, "{name}_count {}", histogram.count())?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace the characters Prometheus does not allow in metric names, or label names which also
+/// do not allow `:`, with `_`.
+fn sanitise_name(key: &str, metric: bool) -> String {
+    let mut name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => c,
+            ':' if metric => c,
+            _ => '_',
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+fn sanitise_labels(labels: &LabelSet) -> LabelSet {
+    labels
+        .iter()
+        .map(|(k, v)| (sanitise_name(k, false), v.clone()))
+        .collect()
+}
+
+/// Replace the file at `path` with `contents`, through a temporary file in the same directory so
+/// that readers never see a partially written file.
+pub(crate) fn write_file_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.tmp", std::process::id()));
+    let temporary = PathBuf::from(temporary);
+    let result =
+        std::fs::write(&temporary, contents).and_then(|_| std::fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Rewrites a Prometheus textfile on an interval, see
+/// [crate::DebugMetricsSafe::spawn_textfile_writer].
+///
+/// The file is written one last time when this is dropped.
+#[must_use = "the textfile writer stops when dropped"]
+pub struct TextfileWriter {
+    pub(crate) stop: Option<Sender<()>>,
+    pub(crate) thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for TextfileWriter {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,674 @@
+use crate::debug_metrics::{CallSites, Event, EventType};
+use crate::histogram::Histogram;
+use crate::json;
+use crate::series::{series_name, Series};
+use crate::span::SpanRecord;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::thread::ThreadId;
+use std::time::Duration;
+
+/// Writes the report of a [crate::DebugMetrics].
+///
+/// The report is written on drop or [crate::DebugMetrics::finish]. Every event that should be
+/// printed is passed to [Reporter::report_event] in order, followed by a single call to
+/// [Reporter::report_summary] with the final state.
+pub trait Reporter: Send {
+    fn report_event(&mut self, writer: &mut dyn Write, event: &Event) -> std::io::Result<()>;
+
+    fn report_summary(
+        &mut self,
+        _writer: &mut dyn Write,
+        _summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The final state of a [crate::DebugMetrics], once all events have been reported.
+pub struct Summary<'a> {
+    pub counts: &'a BTreeMap<String, i64>,
+    pub histograms: &'a BTreeMap<String, Histogram>,
+    /// The series of every metric, see [crate::DebugMetricsConfig::series], summed by
+    /// [crate::DebugMetricsConfig::series_sum_by] if it is set
+    pub series: &'a BTreeMap<String, Series>,
+    pub labels: &'a BTreeMap<String, String>,
+    /// The spans that were closed, in the order they were closed
+    pub spans: &'a [SpanRecord],
+    pub call_sites: &'a CallSites,
+    /// Events that were not kept, because of [crate::DebugMetricsConfig::retention]
+    pub evicted_events: u64,
+}
+
+/// The human readable report, one line per event.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TextReporter {
+    event_times: bool,
+    locations: bool,
+    threads: bool,
+}
+
+impl TextReporter {
+    /// Prefix every event with its sequence number and elapsed time.
+    pub fn with_event_times(mut self, event_times: bool) -> Self {
+        self.event_times = event_times;
+        self
+    }
+
+    /// Prefix every event with its call site, and list the call sites at the end.
+    pub fn with_locations(mut self, locations: bool) -> Self {
+        self.locations = locations;
+        self
+    }
+
+    /// Prefix every event with the name of its thread, or its id if it is not named.
+    pub fn with_threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+}
+
+impl Reporter for TextReporter {
+    fn report_event(&mut self, writer: &mut dyn Write, event: &Event) -> std::io::Result<()> {
+        if self.event_times {
+            write!(writer, "[#{} +{:?}] ", event.sequence, event.elapsed)?;
+        }
+        if self.threads {
+            match &event.thread_name {
+                Some(name) => write!(writer, "<{name}> ")?,
+                None => write!(writer, "<{:?}> ", event.thread)?,
+            }
+        }
+        if self.locations {
+            write!(writer, "{}: ", event.location)?;
+        }
+        if let Some(span) = &event.span {
+            write!(writer, "[{span}] ")?;
+        }
+        if let EventType::Underflow {
+            metric,
+            count,
+            amount,
+            labels,
+        } = &event.event_type
+        {
+            return writeln!(
+                writer,
+                "{metric} underflow: {count} - {amount} :: {labels:?}"
+            );
+        }
+        let (cause, value, dependencies, labels) = match &event.event_type {
+            EventType::MetricChange {
+                count,
+                dependencies,
+                labels,
+                ..
+            } => (None, count.to_string(), dependencies, labels),
+            EventType::LabelChange {
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (None, value.clone(), dependencies, labels),
+            EventType::CascadeMetricChange {
+                cause,
+                count,
+                dependencies,
+                labels,
+                ..
+            } => (Some(cause), count.to_string(), dependencies, labels),
+            EventType::CascadeLabelChange {
+                cause,
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (Some(cause), value.clone(), dependencies, labels),
+            EventType::LabelRemoved {
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (None, format!("removed {value}"), dependencies, labels),
+            EventType::Sample {
+                value,
+                dependencies,
+                labels,
+                ..
+            } => (None, format!("recorded {value}"), dependencies, labels),
+            EventType::Timing {
+                duration,
+                panicked,
+                dependencies,
+                labels,
+                ..
+            } => {
+                let value = if *panicked {
+                    format!("took {duration:?}, panicked")
+                } else {
+                    format!("took {duration:?}")
+                };
+                (None, value, dependencies, labels)
+            }
+            EventType::Underflow { .. } => unreachable!("underflows are reported above"),
+        };
+        let mut all_deps = BTreeMap::new();
+        dependencies.iter().for_each(|(k, v)| {
+            all_deps.insert(k.clone(), v.to_string());
+        });
+        labels.iter().for_each(|(k, v)| {
+            all_deps.insert(k.clone(), v.clone());
+        });
+        let key = event.event_type.key();
+        match cause {
+            None => writeln!(writer, "{key}: {value} :: {all_deps:?}"),
+            Some(cause) => writeln!(writer, "{key} (caused by {cause}): {value} :: {all_deps:?}"),
+        }
+    }
+
+    fn report_summary(
+        &mut self,
+        writer: &mut dyn Write,
+        summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        for (metric, series) in summary.series {
+            for (labels, count) in series {
+                writeln!(writer, "series {}: {count}", series_name(metric, labels))?;
+            }
+        }
+        for (key, histogram) in summary.histograms {
+            writeln!(writer, "histogram {key}: {histogram}")?;
+        }
+        if self.locations {
+            for (location, keys) in summary.call_sites {
+                writeln!(writer, "call site {location}: {keys:?}")?;
+            }
+        }
+        if summary.evicted_events > 0 {
+            writeln!(
+                writer,
+                "{} events evicted by the retention policy",
+                summary.evicted_events
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// One JSON object per line, for `jq` and other tooling.
+///
+/// Every event has a `kind`, `sequence`, `elapsed_ns` and `location`, the `span` it was recorded
+/// in if there was one, the `metric` and `count` or `label` and `value` that changed, the `cause`
+/// for cascades, and the `dependencies` and `labels` that were recorded with it. An `underflow`
+/// has the `metric`, the `count` before and the `amount` subtracted, and the `labels`. A `sample`
+/// has the `metric` and the `value` recorded, and a `timing` the `metric`, `duration_ns` and
+/// whether it `panicked`.
+///
+/// After the events comes a `series` object per series with its `metric`, `labels` and `count`,
+/// and a `histogram` object per histogram with its `count`, `min`, `max`, `mean`, `p50`, `p90` and
+/// `p99`. An `evicted` object with the number of `events` that were not kept is written last, if
+/// there were any.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonLinesReporter {
+    call_sites: bool,
+    threads: bool,
+}
+
+impl JsonLinesReporter {
+    /// Finish with a `call_site` object per call site, with how many times it touched each key.
+    pub fn with_call_sites(mut self, call_sites: bool) -> Self {
+        self.call_sites = call_sites;
+        self
+    }
+
+    /// Include the `thread` id of every event, and its `thread_name` if it is named.
+    pub fn with_threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+}
+
+impl Reporter for JsonLinesReporter {
+    fn report_event(&mut self, writer: &mut dyn Write, event: &Event) -> std::io::Result<()> {
+        let kind = match &event.event_type {
+            EventType::MetricChange { .. } => "metric_change",
+            EventType::LabelChange { .. } => "label_change",
+            EventType::CascadeMetricChange { .. } => "cascade_metric_change",
+            EventType::CascadeLabelChange { .. } => "cascade_label_change",
+            EventType::LabelRemoved { .. } => "label_removed",
+            EventType::Sample { .. } => "sample",
+            EventType::Timing { .. } => "timing",
+            EventType::Underflow { .. } => "underflow",
+        };
+        write!(
+            writer,
+            "{{\"kind\":\"{kind}\",\"sequence\":{},\"elapsed_ns\":{},\"location\":",
+            event.sequence,
+            event.elapsed.as_nanos()
+        )?;
+        json::write_str(writer, &event.location.to_string())?;
+        if self.threads {
+            write!(writer, ",\"thread\":")?;
+            json::write_str(writer, &format!("{:?}", event.thread))?;
+            if let Some(name) = &event.thread_name {
+                write!(writer, ",\"thread_name\":")?;
+                json::write_str(writer, name)?;
+            }
+        }
+        if let Some(span) = &event.span {
+            write!(writer, ",\"span\":")?;
+            json::write_str(writer, span)?;
+        }
+        let (dependencies, labels) = match &event.event_type {
+            EventType::MetricChange {
+                metric,
+                count,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(writer, ",\"count\":{count}")?;
+                (dependencies, labels)
+            }
+            EventType::LabelChange {
+                label,
+                value,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"label\":")?;
+                json::write_str(writer, label)?;
+                write!(writer, ",\"value\":")?;
+                json::write_str(writer, value)?;
+                (dependencies, labels)
+            }
+            EventType::CascadeMetricChange {
+                cause,
+                metric,
+                count,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"cause\":")?;
+                json::write_str(writer, cause)?;
+                write!(writer, ",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(writer, ",\"count\":{count}")?;
+                (dependencies, labels)
+            }
+            EventType::CascadeLabelChange {
+                cause,
+                label,
+                value,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"cause\":")?;
+                json::write_str(writer, cause)?;
+                write!(writer, ",\"label\":")?;
+                json::write_str(writer, label)?;
+                write!(writer, ",\"value\":")?;
+                json::write_str(writer, value)?;
+                (dependencies, labels)
+            }
+            EventType::LabelRemoved {
+                label,
+                value,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"label\":")?;
+                json::write_str(writer, label)?;
+                write!(writer, ",\"value\":")?;
+                json::write_str(writer, value)?;
+                (dependencies, labels)
+            }
+            EventType::Sample {
+                metric,
+                value,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(writer, ",\"value\":{value}")?;
+                (dependencies, labels)
+            }
+            EventType::Timing {
+                metric,
+                duration,
+                panicked,
+                dependencies,
+                labels,
+            } => {
+                write!(writer, ",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(
+                    writer,
+                    ",\"duration_ns\":{},\"panicked\":{panicked}",
+                    duration.as_nanos()
+                )?;
+                (dependencies, labels)
+            }
+            EventType::Underflow {
+                metric,
+                count,
+                amount,
+                labels,
+            } => {
+                write!(writer, ",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(writer, ",\"count\":{count},\"amount\":{amount},\"labels\":")?;
+                json::write_string_object(writer, labels)?;
+                return writeln!(writer, "}}");
+            }
+        };
+        write!(writer, ",\"dependencies\":")?;
+        json::write_number_object(writer, dependencies)?;
+        write!(writer, ",\"labels\":")?;
+        json::write_string_object(writer, labels)?;
+        writeln!(writer, "}}")
+    }
+
+    fn report_summary(
+        &mut self,
+        writer: &mut dyn Write,
+        summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        for (metric, series) in summary.series {
+            for (labels, count) in series {
+                write!(writer, "{{\"kind\":\"series\",\"metric\":")?;
+                json::write_str(writer, metric)?;
+                write!(writer, ",\"labels\":")?;
+                json::write_string_object(writer, labels)?;
+                writeln!(writer, ",\"count\":{count}}}")?;
+            }
+        }
+        for (key, histogram) in summary.histograms {
+            let (Some(min), Some(max), Some(mean)) =
+                (histogram.min(), histogram.max(), histogram.mean())
+            else {
+                continue;
+            };
+            write!(writer, "{{\"kind\":\"histogram\",\"metric\":")?;
+            json::write_str(writer, key)?;
+            writeln!(
+                writer,
+                ",\"count\":{},\"min\":{min},\"max\":{max},\"mean\":{mean},\"p50\":{},\"p90\":{},\"p99\":{}}}",
+                histogram.count(),
+                histogram.percentile(50.0).unwrap_or_default(),
+                histogram.percentile(90.0).unwrap_or_default(),
+                histogram.percentile(99.0).unwrap_or_default(),
+            )?;
+        }
+        if self.call_sites {
+            for (location, keys) in summary.call_sites {
+                write!(writer, "{{\"kind\":\"call_site\",\"location\":")?;
+                json::write_str(writer, &location.to_string())?;
+                write!(writer, ",\"keys\":")?;
+                json::write_number_object(writer, keys)?;
+                writeln!(writer, "}}")?;
+            }
+        }
+        if summary.evicted_events > 0 {
+            writeln!(
+                writer,
+                "{{\"kind\":\"evicted\",\"events\":{}}}",
+                summary.evicted_events
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The Chrome Trace Event Format, a JSON array of trace events to load in `chrome://tracing` or
+/// the Perfetto UI for a timeline per thread.
+///
+/// Metric changes and samples are counter (`C`) events, label changes, label removals and
+/// underflows are instant (`i`) events, timers are complete (`X`) events, as they need not nest
+/// with the spans and timers around them, and closed spans are `B`/`E` pairs. Threads are numbered
+/// in the order they first appear, and named threads get a `thread_name` metadata (`M`) event when
+/// they do.
+#[derive(Clone, Debug, Default)]
+pub struct ChromeTraceReporter {
+    /// Whether the opening `[` has been written
+    started: bool,
+    threads: HashMap<ThreadId, usize>,
+}
+
+impl ChromeTraceReporter {
+    fn separator(&mut self) -> &'static str {
+        let separator = if self.started { ",\n" } else { "[\n" };
+        self.started = true;
+        separator
+    }
+
+    /// The number of `thread`, naming it with a metadata event the first time it appears.
+    fn tid(
+        &mut self,
+        writer: &mut dyn Write,
+        thread: ThreadId,
+        thread_name: Option<&str>,
+    ) -> std::io::Result<usize> {
+        if let Some(tid) = self.threads.get(&thread) {
+            return Ok(*tid);
+        }
+        let tid = self.threads.len() + 1;
+        self.threads.insert(thread, tid);
+        if let Some(name) = thread_name {
+            let separator = self.separator();
+            write!(writer, "{separator}{{\"name\":\"thread_name\",\"ph\":\"M\"")?;
+            let pid = std::process::id();
+            write!(writer, ",\"pid\":{pid},\"tid\":{tid},\"args\":{{\"name\":")?;
+            json::write_str(writer, name)?;
+            write!(writer, "}}}}")?;
+        }
+        Ok(tid)
+    }
+
+    /// Start a trace event object, with the fields that every event has.
+    fn begin_trace_event(
+        &mut self,
+        writer: &mut dyn Write,
+        name: &str,
+        phase: &str,
+        ts: Duration,
+        thread: ThreadId,
+        thread_name: Option<&str>,
+    ) -> std::io::Result<()> {
+        let tid = self.tid(writer, thread, thread_name)?;
+        write!(writer, "{}{{\"name\":", self.separator())?;
+        json::write_str(writer, name)?;
+        write!(
+            writer,
+            ",\"ph\":\"{phase}\",\"ts\":{}.{:03},\"pid\":{},\"tid\":{tid}",
+            ts.as_micros(),
+            ts.subsec_nanos() % 1000,
+            std::process::id()
+        )
+    }
+}
+
+impl Reporter for ChromeTraceReporter {
+    fn report_event(&mut self, writer: &mut dyn Write, event: &Event) -> std::io::Result<()> {
+        let (ts, thread, thread_name) = (event.elapsed, event.thread, event.thread_name.as_deref());
+        match &event.event_type {
+            EventType::MetricChange { metric, count, .. }
+            | EventType::CascadeMetricChange { metric, count, .. } => {
+                self.begin_trace_event(writer, metric, "C", ts, thread, thread_name)?;
+                write!(writer, ",\"args\":{{\"value\":{count}}}}}")
+            }
+            EventType::Sample { metric, value, .. } => {
+                self.begin_trace_event(writer, metric, "C", ts, thread, thread_name)?;
+                write!(writer, ",\"args\":{{\"value\":{value}}}}}")
+            }
+            EventType::LabelChange { label, value, .. } => {
+                self.begin_trace_event(writer, label, "i", ts, thread, thread_name)?;
+                write!(writer, ",\"s\":\"t\",\"args\":{{\"value\":")?;
+                json::write_str(writer, value)?;
+                write!(writer, "}}}}")
+            }
+            EventType::CascadeLabelChange {
+                cause,
+                label,
+                value,
+                ..
+            } => {
+                self.begin_trace_event(writer, label, "i", ts, thread, thread_name)?;
+                write!(writer, ",\"s\":\"t\",\"args\":{{\"value\":")?;
+                json::write_str(writer, value)?;
+                write!(writer, ",\"cause\":")?;
+                json::write_str(writer, cause)?;
+                write!(writer, "}}}}")
+            }
+            EventType::LabelRemoved { label, value, .. } => {
+                let name = format!("{label} removed");
+                self.begin_trace_event(writer, &name, "i", ts, thread, thread_name)?;
+                write!(writer, ",\"s\":\"t\",\"args\":{{\"value\":")?;
+                json::write_str(writer, value)?;
+                write!(writer, "}}}}")
+            }
+            EventType::Timing {
+                metric, duration, ..
+            } => {
+                let start = ts.saturating_sub(*duration);
+                self.begin_trace_event(writer, metric, "X", start, thread, thread_name)?;
+                write!(
+                    writer,
+                    ",\"dur\":{}.{:03}}}",
+                    duration.as_micros(),
+                    duration.subsec_nanos() % 1000
+                )
+            }
+            EventType::Underflow {
+                metric,
+                count,
+                amount,
+                ..
+            } => {
+                let name = format!("{metric} underflow");
+                self.begin_trace_event(writer, &name, "i", ts, thread, thread_name)?;
+                write!(
+                    writer,
+                    ",\"s\":\"t\",\"args\":{{\"count\":{count},\"amount\":{amount}}}}}"
+                )
+            }
+        }
+    }
+
+    fn report_summary(
+        &mut self,
+        writer: &mut dyn Write,
+        summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        // Ends before begins at the same time, and nested spans inside the spans they are nested in
+        let mut boundaries = Vec::with_capacity(summary.spans.len() * 2);
+        for span in summary.spans {
+            let depth = span.stack.matches(';').count() as isize;
+            boundaries.push((span.end, 0, -depth, "E", span));
+            boundaries.push((span.start, 1, depth, "B", span));
+        }
+        boundaries.sort_by_key(|(ts, order, depth, ..)| (*ts, *order, *depth));
+        for (ts, _, _, phase, span) in boundaries {
+            let thread_name = span.thread_name.as_deref();
+            self.begin_trace_event(writer, span.name(), phase, ts, span.thread, thread_name)?;
+            write!(writer, ",\"cat\":\"span\"}}")?;
+        }
+        if self.started {
+            writeln!(writer, "\n]")
+        } else {
+            writeln!(writer, "[]")
+        }
+    }
+}
+
+/// A Graphviz DOT digraph of which keys drive which, to render with `dot -Tsvg`.
+///
+/// Cascades give a solid edge from the metric that caused them to the key that changed, and the
+/// dependencies captured by recording rules give a dashed edge to the key of the rule. Edges are
+/// labelled and weighted by how many printed events they were seen in. Labels are drawn as boxes.
+#[derive(Clone, Debug, Default)]
+pub struct DotReporter {
+    /// `(from, to, dependency)` and how often the edge was seen
+    edges: BTreeMap<(String, String, bool), u64>,
+    keys: BTreeSet<String>,
+    /// Keys that were seen as labels, including labels that were removed since
+    label_keys: BTreeSet<String>,
+}
+
+impl Reporter for DotReporter {
+    fn report_event(&mut self, _writer: &mut dyn Write, event: &Event) -> std::io::Result<()> {
+        let key = event.event_type.key();
+        self.keys.insert(key.to_string());
+        if let EventType::LabelChange { .. }
+        | EventType::CascadeLabelChange { .. }
+        | EventType::LabelRemoved { .. } = &event.event_type
+        {
+            self.label_keys.insert(key.to_string());
+        }
+        let (cause, dependencies) = match &event.event_type {
+            EventType::CascadeMetricChange {
+                cause,
+                dependencies,
+                ..
+            }
+            | EventType::CascadeLabelChange {
+                cause,
+                dependencies,
+                ..
+            } => (Some(cause), Some(dependencies)),
+            EventType::MetricChange { dependencies, .. }
+            | EventType::LabelChange { dependencies, .. }
+            | EventType::LabelRemoved { dependencies, .. }
+            | EventType::Sample { dependencies, .. }
+            | EventType::Timing { dependencies, .. } => (None, Some(dependencies)),
+            EventType::Underflow { .. } => (None, None),
+        };
+        if let Some(cause) = cause {
+            self.keys.insert(cause.clone());
+            let edge = (cause.clone(), key.to_string(), false);
+            *self.edges.entry(edge).or_default() += 1;
+        }
+        for dependency in dependencies.into_iter().flat_map(BTreeMap::keys) {
+            if dependency == key {
+                continue;
+            }
+            self.keys.insert(dependency.clone());
+            let edge = (dependency.clone(), key.to_string(), true);
+            *self.edges.entry(edge).or_default() += 1;
+        }
+        Ok(())
+    }
+
+    fn report_summary(
+        &mut self,
+        writer: &mut dyn Write,
+        summary: &Summary<'_>,
+    ) -> std::io::Result<()> {
+        writeln!(writer, "digraph debug_metrics {{")?;
+        for key in &self.keys {
+            let shape = if summary.labels.contains_key(key) || self.label_keys.contains(key) {
+                "box"
+            } else {
+                "ellipse"
+            };
+            writeln!(writer, "    {} [shape={shape}];", dot_id(key))?;
+        }
+        for ((from, to, dependency), count) in &self.edges {
+            let style = if *dependency { ", style=dashed" } else { "" };
+            writeln!(
+                writer,
+                "    {} -> {} [label=\"{count}\", weight={count}{style}];",
+                dot_id(from),
+                dot_id(to)
+            )?;
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// A quoted DOT identifier.
+fn dot_id(key: &str) -> String {
+    format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,102 @@
+use crate::debug_metrics::KeyPattern;
+
+/// A recording rule that only records an event when its conditions hold, see
+/// [crate::DebugMetricsTrait::add_rule].
+///
+/// A rule without conditions is the same as one added with
+/// [crate::DebugMetricsTrait::add_recording_rule] or
+/// [crate::DebugMetricsTrait::add_pattern_recording_rule], and extends it if there already is one.
+/// A rule with conditions is always a rule of its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordingRuleBuilder {
+    pub(crate) trigger: RuleTrigger,
+    pub(crate) captures: Vec<&'static str>,
+    pub(crate) conditions: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RuleTrigger {
+    Key(String),
+    Pattern(KeyPattern),
+}
+
+impl RecordingRuleBuilder {
+    /// A rule triggered by changes to `key`.
+    pub fn for_key<Key: Into<String>>(key: Key) -> Self {
+        RecordingRuleBuilder {
+            trigger: RuleTrigger::Key(key.into()),
+            captures: Vec::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    /// A rule triggered by changes to every key that matches `trigger`.
+    pub fn for_pattern(trigger: KeyPattern) -> Self {
+        RecordingRuleBuilder {
+            trigger: RuleTrigger::Pattern(trigger),
+            captures: Vec::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    /// Capture the metrics and labels whose keys match any of the regexes with every event.
+    pub fn with_captures(mut self, patterns: &[&'static str]) -> Self {
+        self.captures.extend_from_slice(patterns);
+        self
+    }
+
+    /// Only record an event when the condition holds, as well as any conditions added before.
+    pub fn with_condition(mut self, condition: Condition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// The key or pattern of the trigger, to name the rule in errors.
+    #[cfg(debug_metrics_enabled)]
+    pub(crate) fn name(&self) -> &str {
+        match &self.trigger {
+            RuleTrigger::Key(key) => key,
+            RuleTrigger::Pattern(KeyPattern::Regex(pattern) | KeyPattern::Glob(pattern)) => pattern,
+        }
+    }
+}
+
+/// When a [RecordingRuleBuilder] records an event, checked against the value of the key that
+/// triggered it: the count of a metric, the value of a label, or the value recorded for a sample,
+/// with timings in nanoseconds. A label removal is checked against the value the label had.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    /// The count or sample compares to the constant, e.g. `Compare(Comparison::Greater, 3)`. Never
+    /// holds for labels.
+    Compare(Comparison, i64),
+    /// The label value matches the regex. Never holds for metrics and samples.
+    LabelMatches(&'static str),
+    /// The value differs from the one in the last event that was recorded for the key, or no
+    /// event was recorded for it yet.
+    Changed,
+}
+
+/// How [Condition::Compare] compares the value with the constant, as in `value < constant`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+#[cfg(debug_metrics_enabled)]
+impl Comparison {
+    pub(crate) fn holds(self, value: i64, constant: i64) -> bool {
+        match self {
+            Comparison::Less => value < constant,
+            Comparison::LessOrEqual => value <= constant,
+            Comparison::Equal => value == constant,
+            Comparison::NotEqual => value != constant,
+            Comparison::GreaterOrEqual => value >= constant,
+            Comparison::Greater => value > constant,
+        }
+    }
+}
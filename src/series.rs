@@ -0,0 +1,47 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The labels that identify a series, see [crate::DebugMetricsConfig::series].
+pub type LabelSet = BTreeMap<String, String>;
+
+/// The count of every series of a metric.
+pub type Series = BTreeMap<LabelSet, i64>;
+
+/// Sum the series that have the same values for `labels`, like `sum by (...)` in PromQL.
+///
+/// Labels that are not in `labels` are dropped, so summing by no labels gives the total. Series
+/// without one of the labels are summed together, without that label.
+pub fn sum_by(series: &Series, labels: &[&str]) -> Series {
+    let mut summed = Series::new();
+    for (label_set, count) in series {
+        let group = label_set
+            .iter()
+            .filter(|(k, _)| labels.contains(&k.as_str()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let sum: &mut i64 = summed.entry(group).or_default();
+        *sum = sum.saturating_add(*count);
+    }
+    summed
+}
+
+/// The series in the PromQL selector format, e.g. `requests{route="/a"}`.
+pub(crate) fn series_name(metric: &str, labels: &LabelSet) -> String {
+    let mut name = metric.to_string();
+    if labels.is_empty() {
+        return name;
+    }
+    name.push('{');
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            name.push(',');
+        }
+        let v = v
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let _ = write!(name, "{k}=\"{v}\"");
+    }
+    name.push('}');
+    name
+}
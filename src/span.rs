@@ -0,0 +1,100 @@
+use crate::debug_metrics::DebugMetricsTrait;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::thread::ThreadId;
+use std::time::Duration;
+
+/// A named phase of work, open until dropped, see [DebugMetricsTrait::enter_span].
+///
+/// Spans entered while another span is open on the same thread are nested inside it, and every
+/// event recorded on the thread is attributed to the innermost open span.
+pub struct Span<'a, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    pub(crate) debug_metrics: &'a mut DM,
+    pub(crate) depth: usize,
+}
+
+impl<DM> Span<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    /// The collector, for recording metrics and entering nested spans while the span is open.
+    pub fn debug_metrics(&mut self) -> &mut DM {
+        self.debug_metrics
+    }
+}
+
+impl<DM> Drop for Span<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    fn drop(&mut self) {
+        self.debug_metrics.close_span(self.depth);
+    }
+}
+
+/// A span that was entered and then closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanRecord {
+    /// The names of the span and the spans it was nested in, outermost first, separated by `;`
+    pub stack: String,
+    pub thread: ThreadId,
+    /// The name of the thread the span was entered on, if it was named
+    pub thread_name: Option<String>,
+    /// When the span was entered, according to the clock of the collector
+    pub start: Duration,
+    /// When the span was closed, according to the clock of the collector
+    pub end: Duration,
+}
+
+impl SpanRecord {
+    pub fn name(&self) -> &str {
+        self.stack.rsplit(';').next().unwrap_or_default()
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.end.saturating_sub(self.start)
+    }
+}
+
+/// What the width of a frame in a flamegraph stands for, see [DebugMetricsTrait::folded_stacks].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StackWeight {
+    /// Nanoseconds spent in the span itself, and not in the spans nested in it
+    #[default]
+    Time,
+    /// Events recorded while the span was the innermost open span
+    Events,
+}
+
+/// Weigh every stack by the time spent in it, excluding the time spent in nested stacks.
+pub(crate) fn self_time_by_stack(spans: &[SpanRecord]) -> BTreeMap<String, u64> {
+    let mut stacks: BTreeMap<String, i128> = BTreeMap::new();
+    for span in spans {
+        let nanos = span.duration().as_nanos() as i128;
+        *stacks.entry(span.stack.clone()).or_default() += nanos;
+        if let Some((parent, _)) = span.stack.rsplit_once(';') {
+            *stacks.entry(parent.to_string()).or_default() -= nanos;
+        }
+    }
+    stacks
+        .into_iter()
+        .map(|(stack, nanos)| (stack, u64::try_from(nanos.max(0)).unwrap_or(u64::MAX)))
+        .collect()
+}
+
+/// Write stacks in the folded format of Brendan Gregg's `flamegraph.pl`, one `stack weight` line
+/// per stack, which most flamegraph tools read.
+pub fn write_folded_stacks(
+    writer: &mut dyn Write,
+    stacks: &BTreeMap<String, u64>,
+) -> std::io::Result<()> {
+    for (stack, weight) in stacks {
+        if *weight > 0 {
+            writeln!(writer, "{stack} {weight}")?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use crate::debug_metrics_safe::DebugMetricsSafeTrait;
+use std::marker::PhantomData;
+
+/// A named phase of work, open until dropped, see [DebugMetricsSafeTrait::enter_span].
+///
+/// Holds a clone of the collector, so the collector can still be used while it is open. Spans
+/// belong to the thread that entered them, so unlike [crate::TimerSafe] they can not be sent to
+/// other threads.
+pub struct SpanSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    pub(crate) debug_metrics: DM,
+    pub(crate) depth: usize,
+    pub(crate) not_send: PhantomData<*const ()>,
+}
+
+impl<DM> Drop for SpanSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    fn drop(&mut self) {
+        self.debug_metrics.close_span(self.depth);
+    }
+}
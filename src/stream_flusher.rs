@@ -0,0 +1,21 @@
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+/// Flushes streamed events on an interval, see [crate::DebugMetricsSafe::spawn_stream_flusher].
+///
+/// The events are flushed one last time when this is dropped.
+#[must_use = "the stream flusher stops when dropped"]
+pub struct StreamFlusher {
+    pub(crate) stop: Option<Sender<()>>,
+    pub(crate) thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for StreamFlusher {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
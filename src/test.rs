@@ -1,20 +1,44 @@
-use crate::config::DebugMetricsConfig;
-use crate::debug_metrics::{DebugMetricsTrait, DefaultExt, EventType};
+use crate::clock::ManualClock;
+use crate::config::{
+    DebugMetricsConfig, FlushPolicy, HistogramBuckets, OutputFormat, OutputMode, RetentionPolicy,
+};
+use crate::debug_metrics::{DebugMetricsTrait, DefaultExt, DumpTarget, Event, EventType};
 use crate::debug_metrics_safe::DebugMetricsSafeTrait;
 use crate::label_iter::NoLabels;
-use crate::DebugMetrics;
-use indoc::indoc;
-use log::debug;
+use crate::reporter::{Reporter, Summary};
+use crate::span::{write_folded_stacks, StackWeight};
+use crate::{
+    Comparison, Condition, DebugMetrics, DebugMetricsError, DebugMetricsSharded, Histogram,
+    KeyPattern, LabelSet, RecordingRuleBuilder,
+};
+use indoc::{formatdoc, indoc};
 use std::collections::BTreeMap;
-use std::io::{Cursor, Read};
+use std::io::{Cursor, Read, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Held by the tests that panic on purpose, so that they do not trigger the hook installed by
+/// [panic_hook_dumps_the_flight_recorder].
+static PANICS: Mutex<()> = Mutex::new(());
+
+fn lock_panics() -> MutexGuard<'static, ()> {
+    PANICS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn event_types(events: Vec<Event>) -> Vec<EventType> {
+    events.into_iter().map(|e| e.event_type).collect()
+}
 
 #[test]
 fn metrics_are_displayed_if_no_rules() {
     let mut c = Cursor::new(Vec::new());
+    let line;
     let events = {
-        let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default_on());
+        let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default_on())
+            .with_clock(ManualClock::new());
+        line = line!() + 1;
         debug_metrics.inc("example", NoLabels);
-        debug_metrics.events_for_key("example")
+        event_types(debug_metrics.events_for_key("example"))
     };
     assert_eq!(
         events,
@@ -28,9 +52,11 @@ fn metrics_are_displayed_if_no_rules() {
     c.set_position(0);
     let mut output = String::new();
     c.read_to_string(&mut output).unwrap();
-    let expected = indoc!(
+    let file = file!();
+    let expected = formatdoc!(
         r#"
-        example: 1 :: {}
+        [#0 +0ns] {file}:{line}:23: example: 1 :: {{}}
+        call site {file}:{line}:23: {{"example": 1}}
     "#
     );
     assert_eq!(output, expected);
@@ -39,11 +65,14 @@ fn metrics_are_displayed_if_no_rules() {
 #[test]
 fn can_use_labels() {
     let mut c = Cursor::new(Vec::new());
+    let line;
     let events = {
-        let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default_on());
+        let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default_on())
+            .with_clock(ManualClock::new());
+        line = line!() + 1;
         debug_metrics.set_label("stage", "zero");
         debug_metrics.set("example", 42, vec![("stage", "one")].into_iter());
-        debug_metrics.events_for_key("example")
+        event_types(debug_metrics.events_for_key("example"))
     };
     assert_eq!(
         events,
@@ -66,22 +95,27 @@ fn can_use_labels() {
     c.set_position(0);
     let mut output = String::new();
     c.read_to_string(&mut output).unwrap();
-    let expected = indoc!(
+    let (file, next) = (file!(), line + 1);
+    let expected = formatdoc!(
         r#"
-        stage: zero :: {"stage": "zero"}
-        stage (caused by example): one :: {"stage": "one"}
-        example: 42 :: {"stage": "one"}
+        [#0 +0ns] {file}:{line}:23: stage: zero :: {{"stage": "zero"}}
+        [#1 +0ns] {file}:{next}:23: stage (caused by example): one :: {{"stage": "one"}}
+        [#2 +0ns] {file}:{next}:23: example: 42 :: {{"stage": "one"}}
+        call site {file}:{line}:23: {{"stage": 1}}
+        call site {file}:{next}:23: {{"example": 1, "stage": 1}}
     "#
     );
     assert_eq!(output, expected);
 }
 
+type CursorMetrics<'a> = DebugMetrics<&'a mut Cursor<Vec<u8>>>;
+
 #[test]
 fn label_changes_get_recorded_as_events() {
     struct TestCase {
         name: &'static str,
         config: DebugMetricsConfig,
-        pre_setup: &'static dyn Fn(&mut DebugMetrics<&mut Cursor<Vec<u8>>>),
+        pre_setup: &'static dyn Fn(&mut CursorMetrics),
         events: Vec<EventType>,
         output: &'static str,
     }
@@ -118,8 +152,12 @@ fn label_changes_get_recorded_as_events() {
         },
         TestCase {
             name: "Enabled capture all config and no recording rule",
-            config: DebugMetricsConfig::default_on(),
-            pre_setup: &|debug_metrics| {},
+            // The outputs are static, so they leave the locations to the tests of call sites
+            config: DebugMetricsConfig {
+                report_locations: false,
+                ..DebugMetricsConfig::default_on()
+            },
+            pre_setup: &|_debug_metrics| {},
             events: vec![
                 EventType::LabelChange {
                     label: "stage".to_string(),
@@ -138,9 +176,9 @@ fn label_changes_get_recorded_as_events() {
             ],
             output: indoc!(
                 r#"
-                stage: zero :: {"stage": "zero"}
-                stage (caused by metric): one :: {"stage": "one"}
-                metric: 1 :: {"stage": "one"}
+                [#0 +0ns] stage: zero :: {"stage": "zero"}
+                [#1 +0ns] stage (caused by metric): one :: {"stage": "one"}
+                [#2 +0ns] metric: 1 :: {"stage": "one"}
                 "#
             ),
         },
@@ -148,12 +186,13 @@ fn label_changes_get_recorded_as_events() {
     for case in cases {
         let mut c = Cursor::new(Vec::new());
         let events = {
-            let mut debug_metrics = DebugMetrics::new(&mut c, case.config);
+            let mut debug_metrics =
+                DebugMetrics::new(&mut c, case.config).with_clock(ManualClock::new());
             let pre_setup = case.pre_setup;
             pre_setup(&mut debug_metrics);
             debug_metrics.set_label("stage", "zero");
             debug_metrics.inc("metric", vec![("stage", "one")].into_iter());
-            debug_metrics.events_for_key("stage")
+            event_types(debug_metrics.events_for_key("stage"))
         };
         assert_eq!(&events, &case.events, "{}", case.name);
         c.set_position(0);
@@ -175,7 +214,7 @@ fn test_drop_hook() {
         42
     };
     assert_eq!(some_val, 42);
-    let events = debug_metrics.events_for_key("dropped");
+    let events = event_types(debug_metrics.events_for_key("dropped"));
     assert_eq!(
         events,
         vec![EventType::MetricChange {
@@ -199,7 +238,7 @@ fn test_drop_hook_safe() {
         42
     };
     assert_eq!(some_val, 42);
-    let events = debug_metrics.events_for_key("dropped");
+    let events = event_types(debug_metrics.events_for_key("dropped"));
     assert_eq!(
         events,
         vec![EventType::MetricChange {
@@ -210,3 +249,1849 @@ fn test_drop_hook_safe() {
         }]
     )
 }
+
+#[test]
+fn recording_rules_match_keys_added_before_and_after_the_rule() {
+    let mut c = Cursor::new(Vec::new());
+    let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default());
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.add_recording_rule("query", &["^db\\."]);
+    debug_metrics.inc("db.writes", NoLabels);
+    debug_metrics.set_label("db.pool", "primary");
+    debug_metrics.inc("cache.hits", NoLabels);
+    debug_metrics.inc("query", NoLabels);
+    // Extending a rule rescans the existing keys
+    debug_metrics.add_recording_rule("query", &["^cache\\."]);
+    debug_metrics.inc("query", NoLabels);
+    let events = event_types(debug_metrics.events_for_key("query"));
+    assert_eq!(
+        events,
+        vec![
+            EventType::MetricChange {
+                metric: "query".to_string(),
+                count: 1,
+                dependencies: BTreeMap::from([
+                    ("db.reads".to_string(), 1),
+                    ("db.writes".to_string(), 1),
+                ]),
+                labels: BTreeMap::from([("db.pool".to_string(), "primary".to_string())]),
+            },
+            EventType::MetricChange {
+                metric: "query".to_string(),
+                count: 2,
+                dependencies: BTreeMap::from([
+                    ("cache.hits".to_string(), 1),
+                    ("db.reads".to_string(), 1),
+                    ("db.writes".to_string(), 1),
+                ]),
+                labels: BTreeMap::from([("db.pool".to_string(), "primary".to_string())]),
+            },
+        ]
+    );
+}
+
+#[test]
+fn invalid_recording_rules_are_rejected_at_registration() {
+    let mut c = Cursor::new(Vec::new());
+    let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default());
+    debug_metrics.add_recording_rule("query", &["^db\\."]);
+    let err = debug_metrics
+        .try_add_recording_rule("query", &["^cache\\.", "(unclosed"])
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            DebugMetricsError::InvalidRecordingRule { metric, pattern, .. }
+                if metric == "query" && *pattern == "(unclosed"
+        ),
+        "{err:?}"
+    );
+    // The existing rule is untouched and keeps working
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.inc("cache.hits", NoLabels);
+    debug_metrics.inc("query", NoLabels);
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("query")),
+        vec![EventType::MetricChange {
+            metric: "query".to_string(),
+            count: 1,
+            dependencies: BTreeMap::from([("db.reads".to_string(), 1)]),
+            labels: Default::default(),
+        }]
+    );
+    // A rejected new rule is not registered at all
+    let safe = debug_metrics.safe();
+    assert!(safe.try_add_recording_rule("other", &["["]).is_err());
+    safe.inc("other", NoLabels);
+    assert_eq!(event_types(safe.events_for_key("other")), vec![]);
+}
+
+/// A writer that fails every write, like a closed pipe
+#[derive(Debug)]
+struct BrokenPipe;
+
+impl Write for BrokenPipe {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+#[test]
+fn finish_returns_the_writer_with_the_report() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    let output = debug_metrics.finish().unwrap();
+    let file = file!();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        format!("[#0 +0ns] {file}:{line}:19: example: 1 :: {{}}\ncall site {file}:{line}:19: {{\"example\": 1}}\n")
+    );
+}
+
+#[test]
+fn finish_after_flush_report_does_not_write_the_report_again() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    debug_metrics.flush_report().unwrap();
+    debug_metrics.inc("example", NoLabels);
+    debug_metrics.flush_report().unwrap();
+    let output = debug_metrics.finish().unwrap();
+    let file = file!();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        format!("[#0 +0ns] {file}:{line}:19: example: 1 :: {{}}\ncall site {file}:{line}:19: {{\"example\": 1}}\n")
+    );
+}
+
+#[test]
+fn writer_failures_are_returned_from_finish_and_ignored_on_drop() {
+    let mut debug_metrics = DebugMetrics::new(BrokenPipe, DebugMetricsConfig::default_on());
+    debug_metrics.inc("example", NoLabels);
+    let err = debug_metrics.finish().unwrap_err();
+    assert!(
+        matches!(&err, DebugMetricsError::Writer(e) if e.kind() == std::io::ErrorKind::BrokenPipe),
+        "{err:?}"
+    );
+
+    let mut debug_metrics = DebugMetrics::new(BrokenPipe, DebugMetricsConfig::default_on());
+    debug_metrics.inc("example", NoLabels);
+    drop(debug_metrics);
+}
+
+#[test]
+fn nothing_is_written_when_dropped_during_a_panic() {
+    let _panics = lock_panics();
+    let mut c = Cursor::new(Vec::new());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut debug_metrics = DebugMetrics::new(&mut c, DebugMetricsConfig::default_on());
+        debug_metrics.inc("example", NoLabels);
+        panic!("unwinding with metrics in scope");
+    }));
+    assert!(result.is_err());
+    assert!(c.into_inner().is_empty());
+}
+
+#[test]
+fn events_have_sequence_numbers_and_times() {
+    let clock = ManualClock::new();
+    let mut debug_metrics =
+        DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).with_clock(clock.clone());
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    clock.advance(Duration::from_micros(1500));
+    debug_metrics.set_label("stage", "one");
+    clock.advance(Duration::from_secs(2));
+    debug_metrics.inc("example", NoLabels);
+    let events: Vec<(u64, Duration, EventType)> = debug_metrics
+        .events_for_key("example")
+        .into_iter()
+        .map(|e| (e.sequence, e.elapsed, e.event_type))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (
+                0,
+                Duration::ZERO,
+                EventType::MetricChange {
+                    metric: "example".to_string(),
+                    count: 1,
+                    dependencies: Default::default(),
+                    labels: Default::default(),
+                }
+            ),
+            (
+                2,
+                Duration::from_micros(2_001_500),
+                EventType::MetricChange {
+                    metric: "example".to_string(),
+                    count: 2,
+                    dependencies: Default::default(),
+                    labels: BTreeMap::from([("stage".to_string(), "one".to_string())]),
+                }
+            ),
+        ]
+    );
+    let output = debug_metrics.finish().unwrap();
+    let (file, label_line, last) = (file!(), line + 2, line + 4);
+    let expected = formatdoc!(
+        r#"
+        [#0 +0ns] {file}:{line}:19: example: 1 :: {{}}
+        [#1 +1.5ms] {file}:{label_line}:19: stage: one :: {{"stage": "one"}}
+        [#2 +2.0015s] {file}:{last}:19: example: 2 :: {{"stage": "one"}}
+        call site {file}:{line}:19: {{"example": 1}}
+        call site {file}:{label_line}:19: {{"stage": 1}}
+        call site {file}:{last}:19: {{"example": 1}}
+    "#
+    );
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[test]
+fn events_record_their_call_site() {
+    let config = DebugMetricsConfig {
+        report_locations: true,
+        ..DebugMetricsConfig::default_on()
+    };
+    let debug_metrics = DebugMetrics::new(Vec::new(), config).safe();
+    let inc_line = line!() + 2;
+    for _ in 0..2 {
+        debug_metrics.inc("example", vec![("stage", "one")].into_iter());
+    }
+    let set_line = line!() + 1;
+    debug_metrics.set("example", 5, NoLabels);
+
+    let events = debug_metrics.events_for_key("example");
+    let lines: Vec<(&str, u32)> = events
+        .iter()
+        .map(|e| (e.location.file(), e.location.line()))
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            (file!(), inc_line),
+            (file!(), inc_line),
+            (file!(), inc_line),
+            (file!(), inc_line),
+            (file!(), set_line),
+        ]
+    );
+    let call_sites: Vec<(u32, BTreeMap<String, u64>)> = debug_metrics
+        .call_sites()
+        .into_iter()
+        .map(|(location, keys)| (location.line(), keys))
+        .collect();
+    assert_eq!(
+        call_sites,
+        vec![
+            (
+                inc_line,
+                BTreeMap::from([("example".to_string(), 2), ("stage".to_string(), 2)])
+            ),
+            (set_line, BTreeMap::from([("example".to_string(), 1)])),
+        ]
+    );
+}
+
+#[test]
+fn call_sites_are_printed_in_the_report() {
+    let config = DebugMetricsConfig {
+        report_locations: true,
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config).with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let (file, column) = (file!(), 19);
+    assert_eq!(
+        output,
+        format!(
+            "[#0 +0ns] {file}:{line}:{column}: example: 1 :: {{}}\ncall site {file}:{line}:{column}: {{\"example\": 1}}\n"
+        )
+    );
+}
+
+#[test]
+fn json_lines_output_has_one_escaped_object_per_event() {
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::JsonLines,
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config).with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.set_label("stage", "say \"hi\"\n\t\u{1}");
+    debug_metrics.add_recording_rule("example", &["stage"]);
+    debug_metrics.set("example", 42, vec![("path", "C:\\tmp")].into_iter());
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let location = |line: u32, column: u32| format!("{}:{line}:{column}", file!());
+    let expected = [
+        format!(
+            r#"{{"kind":"label_change","sequence":0,"elapsed_ns":0,"location":"{}","label":"stage","value":"say \"hi\"\n\t\u0001","dependencies":{{}},"labels":{{"stage":"say \"hi\"\n\t\u0001"}}}}"#,
+            location(line, 19)
+        ),
+        format!(
+            r#"{{"kind":"cascade_label_change","sequence":1,"elapsed_ns":0,"location":"{}","cause":"example","label":"path","value":"C:\\tmp","dependencies":{{}},"labels":{{"path":"C:\\tmp","stage":"say \"hi\"\n\t\u0001"}}}}"#,
+            location(line + 2, 19)
+        ),
+        format!(
+            r#"{{"kind":"metric_change","sequence":2,"elapsed_ns":0,"location":"{}","metric":"example","count":42,"dependencies":{{}},"labels":{{"path":"C:\\tmp","stage":"say \"hi\"\n\t\u0001"}}}}"#,
+            location(line + 2, 19)
+        ),
+        format!(
+            r#"{{"kind":"call_site","location":"{}","keys":{{"stage":1}}}}"#,
+            location(line, 19)
+        ),
+        format!(
+            r#"{{"kind":"call_site","location":"{}","keys":{{"example":1,"path":1}}}}"#,
+            location(line + 2, 19)
+        ),
+    ];
+    assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn custom_reporters_receive_events_and_final_state() {
+    struct CountingReporter {
+        events: usize,
+    }
+
+    impl Reporter for CountingReporter {
+        fn report_event(&mut self, _writer: &mut dyn Write, _event: &Event) -> std::io::Result<()> {
+            self.events += 1;
+            Ok(())
+        }
+
+        fn report_summary(
+            &mut self,
+            writer: &mut dyn Write,
+            summary: &Summary<'_>,
+        ) -> std::io::Result<()> {
+            writeln!(
+                writer,
+                "{} events, counts {:?}, labels {:?}",
+                self.events, summary.counts, summary.labels
+            )
+        }
+    }
+
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_reporter(CountingReporter { events: 0 });
+    debug_metrics.inc("example", vec![("stage", "one")].into_iter());
+    debug_metrics.inc("example", NoLabels);
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    assert_eq!(
+        output,
+        "3 events, counts {\"example\": 2}, labels {\"stage\": \"one\"}\n"
+    );
+}
+
+/// A writer that can be inspected while the metrics still own a clone of it
+#[derive(Clone, Debug, Default)]
+struct SharedBuffer {
+    inner: std::sync::Arc<std::sync::Mutex<(Vec<u8>, usize)>>,
+}
+
+impl SharedBuffer {
+    fn output(&self) -> String {
+        String::from_utf8(self.inner.lock().unwrap().0.clone()).unwrap()
+    }
+
+    fn flushes(&self) -> usize {
+        self.inner.lock().unwrap().1
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.lock().unwrap().0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.lock().unwrap().1 += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn streaming_writes_events_as_they_happen() {
+    let buffer = SharedBuffer::default();
+    let config = DebugMetricsConfig {
+        output_mode: OutputMode::Streaming(FlushPolicy::EveryNEvents(2)),
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics =
+        DebugMetrics::new(buffer.clone(), config).with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    let first = format!("[#0 +0ns] {}:{line}:19: example: 1 :: {{}}\n", file!());
+    assert_eq!(buffer.output(), first);
+    assert_eq!(buffer.flushes(), 0);
+    debug_metrics.inc("example", NoLabels);
+    let both = format!(
+        "{first}[#1 +0ns] {}:{}:19: example: 2 :: {{}}\n",
+        file!(),
+        line + 4
+    );
+    assert_eq!(buffer.output(), both);
+    assert_eq!(buffer.flushes(), 1);
+    // Events can still be queried
+    assert_eq!(debug_metrics.events_for_key("example").len(), 2);
+    // Finishing does not repeat the streamed events, only adds the call sites
+    debug_metrics.finish().unwrap();
+    assert_eq!(
+        buffer.output(),
+        format!(
+            "{both}call site {file}:{line}:19: {{\"example\": 1}}\n\
+             call site {file}:{}:19: {{\"example\": 1}}\n",
+            line + 4,
+            file = file!()
+        )
+    );
+    assert_eq!(buffer.flushes(), 2);
+}
+
+#[test]
+fn streaming_flushes_on_an_interval() {
+    let buffer = SharedBuffer::default();
+    let clock = ManualClock::new();
+    let config = DebugMetricsConfig {
+        output_mode: OutputMode::Streaming(FlushPolicy::Interval(Duration::from_secs(1))),
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics = DebugMetrics::new(buffer.clone(), config).with_clock(clock.clone());
+    debug_metrics.inc("example", NoLabels);
+    clock.advance(Duration::from_millis(500));
+    debug_metrics.inc("example", NoLabels);
+    assert_eq!(buffer.flushes(), 0);
+    clock.advance(Duration::from_millis(500));
+    debug_metrics.inc("example", NoLabels);
+    assert_eq!(buffer.flushes(), 1);
+    clock.advance(Duration::from_millis(999));
+    debug_metrics.inc("example", NoLabels);
+    assert_eq!(buffer.flushes(), 1);
+    assert_eq!(buffer.output().lines().count(), 4);
+}
+
+#[test]
+fn stream_flusher_flushes_an_idle_collector() {
+    let buffer = SharedBuffer::default();
+    let config = DebugMetricsConfig {
+        output_mode: OutputMode::Streaming(FlushPolicy::Interval(Duration::from_secs(3600))),
+        ..DebugMetricsConfig::default_on()
+    };
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config)
+        .with_clock(ManualClock::new())
+        .safe();
+    let flusher = debug_metrics.spawn_stream_flusher(Duration::from_secs(3600));
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    assert_eq!(buffer.flushes(), 0);
+    // Stopping the flusher flushes one last time, without waiting for another event
+    drop(flusher);
+    assert_eq!(buffer.flushes(), 1);
+    assert_eq!(
+        buffer.output(),
+        format!("[#0 +0ns] {}:{line}:19: example: 1 :: {{}}\n", file!())
+    );
+}
+
+#[test]
+fn streaming_only_writes_printed_events() {
+    let buffer = SharedBuffer::default();
+    let config = DebugMetricsConfig {
+        output_mode: OutputMode::Streaming(FlushPolicy::EveryEvent),
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(buffer.clone(), config);
+    debug_metrics.add_recording_rule("quiet", &[]);
+    debug_metrics.add_recording_rule("loud", &[]);
+    debug_metrics.add_drop_hook("loud");
+    debug_metrics.inc("quiet", NoLabels);
+    debug_metrics.inc("loud", NoLabels);
+    assert_eq!(buffer.output(), "loud: 1 :: {}\n");
+    assert_eq!(buffer.flushes(), 1);
+}
+
+#[test]
+fn retention_policies_limit_the_kept_events() {
+    let run = |retention: RetentionPolicy| {
+        let config = DebugMetricsConfig {
+            retention,
+            ..DebugMetricsConfig::default_on()
+        };
+        let mut debug_metrics =
+            DebugMetrics::new(Vec::new(), config).with_clock(ManualClock::new());
+        let location = format!("{}:{}:27", file!(), line!() + 2);
+        for _ in 0..5 {
+            debug_metrics.inc("example", NoLabels);
+        }
+        let kept: Vec<u64> = debug_metrics
+            .events_for_key("example")
+            .iter()
+            .map(|e| e.sequence)
+            .collect();
+        let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+        (kept, output, location)
+    };
+
+    let (kept, output, location) = run(RetentionPolicy::KeepLast(2));
+    assert_eq!(kept, vec![3, 4]);
+    // Call sites count every change, including those of evicted events
+    assert_eq!(
+        output,
+        formatdoc!(
+            r#"
+            [#3 +0ns] {location}: example: 4 :: {{}}
+            [#4 +0ns] {location}: example: 5 :: {{}}
+            call site {location}: {{"example": 5}}
+            3 events evicted by the retention policy
+        "#
+        )
+    );
+
+    let (kept, output, location) = run(RetentionPolicy::KeepFirst(2));
+    assert_eq!(kept, vec![0, 1]);
+    assert_eq!(
+        output,
+        formatdoc!(
+            r#"
+            [#0 +0ns] {location}: example: 1 :: {{}}
+            [#1 +0ns] {location}: example: 2 :: {{}}
+            call site {location}: {{"example": 5}}
+            3 events evicted by the retention policy
+        "#
+        )
+    );
+
+    let (kept, _, _) = run(RetentionPolicy::Unbounded);
+    assert_eq!(kept, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn byte_budget_keeps_the_most_recent_events_that_fit() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on());
+    debug_metrics.inc("example", NoLabels);
+    let size = debug_metrics.events_for_key("example")[0].approximate_size();
+
+    let config = DebugMetricsConfig {
+        retention: RetentionPolicy::ByteBudget(size * 3 + size / 2),
+        output_format: OutputFormat::JsonLines,
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    for _ in 0..10 {
+        debug_metrics.inc("example", NoLabels);
+    }
+    let kept: Vec<u64> = debug_metrics
+        .events_for_key("example")
+        .iter()
+        .map(|e| e.sequence)
+        .collect();
+    assert_eq!(kept, vec![7, 8, 9]);
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    assert_eq!(
+        output.lines().last(),
+        Some(r#"{"kind":"evicted","events":7}"#)
+    );
+}
+
+#[test]
+fn panic_hook_dumps_the_flight_recorder() {
+    let _panics = lock_panics();
+    let buffer = SharedBuffer::default();
+    let config = DebugMetricsConfig {
+        retention: RetentionPolicy::KeepLast(2),
+        ..DebugMetricsConfig::default_on()
+    };
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config)
+        .with_clock(ManualClock::new())
+        .safe();
+    let hook = debug_metrics.install_panic_hook(DumpTarget::Writer);
+    let line = line!() + 2;
+    for _ in 0..3 {
+        debug_metrics.inc("example", vec![("stage", "one")].into_iter());
+    }
+    let panic_in_thread = || {
+        let thread_metrics = debug_metrics.clone();
+        let result = std::thread::spawn(move || {
+            let _metrics = thread_metrics;
+            panic!("something went wrong");
+        })
+        .join();
+        assert!(result.is_err());
+    };
+    panic_in_thread();
+    drop(hook);
+    let location = format!("{}:{line}:23", file!());
+    let output = buffer.output();
+    assert_eq!(
+        output,
+        format!(
+            indoc!(
+                r#"
+                    debug metrics dump, 2 events kept, 4 evicted:
+                    [#4 +0ns] {location}: stage (caused by example): one :: {{"stage": "one"}}
+                    [#5 +0ns] {location}: example: 3 :: {{"stage": "one"}}
+                    counts: {{"example": 3}}
+                    labels: {{"stage": "one"}}
+                "#
+            ),
+            location = location
+        )
+    );
+    // Once the guard is dropped, panics are no longer dumped
+    panic_in_thread();
+    assert_eq!(buffer.output(), output);
+}
+
+#[test]
+fn global_collector_with_macros() {
+    // The only test that uses the global collector, as it can only be initialised once
+    let buffer = SharedBuffer::default();
+    // The global collector cannot be given a manual clock, so its times would differ between runs
+    let config = DebugMetricsConfig {
+        report_event_times: false,
+        ..DebugMetricsConfig::default_on()
+    };
+    let guard = crate::init_global(buffer.clone(), config).unwrap();
+    assert!(matches!(
+        crate::init_global(Vec::new(), DebugMetricsConfig::default()),
+        Err(DebugMetricsError::GlobalAlreadyInitialised)
+    ));
+    let line = line!() + 1;
+    crate::dm_label!("stage", "one");
+    crate::dm_inc!("requests");
+    crate::dm_inc!("requests", "route" => "/a");
+    crate::dm_set!("queue_depth", 3, "queue" => "io", "stage" => "two");
+    assert_eq!(crate::global().events_for_key("requests").len(), 3);
+    assert_eq!(buffer.output(), "");
+    drop(guard);
+    // Each macro is located where it is called
+    let at = |offset: u32| format!("{}:{}:5", file!(), line + offset);
+    let (label, inc, inc_route, set) = (at(0), at(1), at(2), at(3));
+    assert_eq!(
+        buffer.output(),
+        formatdoc!(
+            r#"
+            {label}: stage: one :: {{"stage": "one"}}
+            {inc}: requests: 1 :: {{"stage": "one"}}
+            {inc_route}: route (caused by requests): /a :: {{"route": "/a", "stage": "one"}}
+            {inc_route}: requests: 2 :: {{"route": "/a", "stage": "one"}}
+            {set}: queue (caused by queue_depth): io :: {{"queue": "io", "route": "/a", "stage": "one"}}
+            {set}: stage (caused by queue_depth): two :: {{"queue": "io", "route": "/a", "stage": "two"}}
+            {set}: queue_depth: 3 :: {{"queue": "io", "route": "/a", "stage": "two"}}
+            call site {label}: {{"stage": 1}}
+            call site {inc}: {{"requests": 1}}
+            call site {inc_route}: {{"requests": 1, "route": 1}}
+            call site {set}: {{"queue": 1, "queue_depth": 1, "stage": 1}}
+        "#
+        )
+    );
+    // The report is only written once
+    crate::dm_inc!("requests");
+    crate::flush_global().unwrap();
+    assert_eq!(buffer.output().lines().count(), 11);
+}
+
+#[test]
+fn counters_can_be_decremented_and_added_to() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on());
+    debug_metrics.add("in_flight", 5, NoLabels);
+    debug_metrics.dec("in_flight", NoLabels);
+    debug_metrics.sub("in_flight", 2, NoLabels);
+    debug_metrics.inc("in_flight", NoLabels);
+    let counts: Vec<i64> = event_types(debug_metrics.events_for_key("in_flight"))
+        .into_iter()
+        .map(|e| match e {
+            EventType::MetricChange { count, .. } => count,
+            e => panic!("unexpected event {e:?}"),
+        })
+        .collect();
+    assert_eq!(counts, vec![5, 4, 2, 3]);
+}
+
+#[test]
+fn counters_report_underflow_instead_of_wrapping() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.set_label("stage", "drain");
+    debug_metrics.inc("queue_depth", NoLabels);
+    debug_metrics.sub("queue_depth", 3, NoLabels);
+    debug_metrics.dec("queue_depth", NoLabels);
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("queue_depth")),
+        vec![
+            EventType::Underflow {
+                metric: "queue_depth".to_string(),
+                count: 1,
+                amount: 3,
+                labels: BTreeMap::from([("stage".to_string(), "drain".to_string())]),
+            },
+            EventType::Underflow {
+                metric: "queue_depth".to_string(),
+                count: 0,
+                amount: 1,
+                labels: BTreeMap::from([("stage".to_string(), "drain".to_string())]),
+            },
+        ]
+    );
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let expected = indoc!(
+        r#"
+        queue_depth underflow: 1 - 3 :: {"stage": "drain"}
+        queue_depth underflow: 0 - 1 :: {"stage": "drain"}
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn gauges_can_go_negative() {
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).safe();
+    debug_metrics.set_gauge("temperature", 2, NoLabels);
+    debug_metrics.sub("temperature", 5, NoLabels);
+    debug_metrics.add("temperature", 1, NoLabels);
+    let events = event_types(debug_metrics.events_for_key("temperature"));
+    assert_eq!(events.len(), 3);
+    assert_eq!(
+        events[1],
+        EventType::MetricChange {
+            metric: "temperature".to_string(),
+            count: -3,
+            dependencies: Default::default(),
+            labels: Default::default(),
+        }
+    );
+    assert!(matches!(
+        events[2],
+        EventType::MetricChange { count: -2, .. }
+    ));
+}
+
+#[test]
+fn histograms_are_summarised_in_the_report() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.add_recording_rule("batch_size", &[]);
+    debug_metrics.add_drop_hook("batch_size");
+    for size in (1..=10).rev() {
+        debug_metrics.record("batch_size", size, NoLabels);
+    }
+    debug_metrics.record("retries", 0, vec![("stage", "flush")].into_iter());
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("batch_size"))[0],
+        EventType::Sample {
+            metric: "batch_size".to_string(),
+            value: 10,
+            dependencies: Default::default(),
+            labels: Default::default(),
+        }
+    );
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    assert_eq!(
+        output.lines().collect::<Vec<_>>()[9..],
+        [
+            "batch_size: recorded 1 :: {}",
+            "histogram batch_size: count=10 min=1 max=10 mean=5.50 p50=5 p90=9 p99=10",
+            "histogram retries: count=1 min=0 max=0 mean=0.00 p50=0 p90=0 p99=0",
+        ]
+    );
+}
+
+#[test]
+fn log_linear_histograms_keep_percentiles_within_their_precision() {
+    let mut histogram = Histogram::new(HistogramBuckets::LogLinear { sub_bucket_bits: 4 });
+    for latency in 1_000..=100_000 {
+        histogram.record(latency);
+    }
+    assert_eq!(histogram.count(), 99_001);
+    assert_eq!(histogram.min(), Some(1_000));
+    assert_eq!(histogram.max(), Some(100_000));
+    assert_eq!(histogram.mean(), Some(50_500.0));
+    for (percentile, exact) in [(50.0, 50_500.0), (90.0, 90_100.0), (99.0, 99_010.0)] {
+        let estimate = histogram.percentile(percentile).unwrap() as f64;
+        assert!(
+            (estimate - exact).abs() / exact <= 1.0 / 16.0,
+            "p{percentile}: {estimate} vs {exact}"
+        );
+    }
+    assert!(histogram.buckets().len() < 150);
+}
+
+#[test]
+fn explicit_histogram_buckets() {
+    let mut histogram = Histogram::new(HistogramBuckets::Explicit(&[1, 10, 100]));
+    for value in [0, 1, 5, 10, 50, 1000] {
+        histogram.record(value);
+    }
+    assert_eq!(
+        histogram.buckets(),
+        &BTreeMap::from([(1, 2), (10, 2), (100, 1), (u64::MAX, 1)])
+    );
+    assert_eq!(histogram.percentile(50.0), Some(10));
+    assert_eq!(histogram.percentile(100.0), Some(1000));
+    assert_eq!(
+        Histogram::new(HistogramBuckets::default()).percentile(50.0),
+        None
+    );
+}
+
+#[test]
+fn timers_record_how_long_a_scope_took() {
+    let clock = ManualClock::new();
+    let mut debug_metrics =
+        DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).with_clock(clock.clone());
+    let line = line!() + 2;
+    {
+        let mut timer = debug_metrics.start_timer("flush", vec![("stage", "one")].into_iter());
+        timer.debug_metrics().inc("pages", NoLabels);
+        clock.advance(Duration::from_millis(5));
+    }
+    let timer = debug_metrics.start_timer("flush", NoLabels);
+    clock.advance(Duration::from_millis(2));
+    assert_eq!(timer.stop(), Duration::from_millis(2));
+    let timer = debug_metrics.start_timer("flush", NoLabels);
+    clock.advance(Duration::from_millis(1));
+    timer.discard();
+    let events = debug_metrics.events_for_key("flush");
+    assert_eq!(events[1].location.line(), line);
+    assert_eq!(
+        event_types(events),
+        vec![
+            EventType::CascadeLabelChange {
+                cause: "flush".to_string(),
+                label: "stage".to_string(),
+                value: "one".to_string(),
+                dependencies: Default::default(),
+                labels: BTreeMap::from([("stage".to_string(), "one".to_string())]),
+            },
+            EventType::Timing {
+                metric: "flush".to_string(),
+                duration: Duration::from_millis(5),
+                panicked: false,
+                dependencies: Default::default(),
+                labels: BTreeMap::from([("stage".to_string(), "one".to_string())]),
+            },
+            EventType::Timing {
+                metric: "flush".to_string(),
+                duration: Duration::from_millis(2),
+                panicked: false,
+                dependencies: Default::default(),
+                labels: BTreeMap::from([("stage".to_string(), "one".to_string())]),
+            },
+        ]
+    );
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    assert!(
+        output.contains(r#"flush: took 5ms :: {"stage": "one"}"#),
+        "{output}"
+    );
+    assert!(
+        output.contains("histogram flush: count=2 min=2000000 max=5000000"),
+        "{output}"
+    );
+}
+
+#[test]
+fn timers_record_whether_the_scope_panicked() {
+    let _panics = lock_panics();
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_clock(ManualClock::new())
+        .safe();
+    let thread_metrics = debug_metrics.clone();
+    let result = std::thread::spawn(move || {
+        let _timer = thread_metrics.start_timer("compaction", NoLabels);
+        panic!("compaction failed");
+    })
+    .join();
+    assert!(result.is_err());
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("compaction")),
+        vec![EventType::Timing {
+            metric: "compaction".to_string(),
+            duration: Duration::ZERO,
+            panicked: true,
+            dependencies: Default::default(),
+            labels: Default::default(),
+        }]
+    );
+}
+
+#[test]
+fn spans_nest_and_attribute_events() {
+    let clock = ManualClock::new();
+    let mut debug_metrics =
+        DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).with_clock(clock.clone());
+    {
+        let mut pipeline = debug_metrics.enter_span("pipeline");
+        pipeline.debug_metrics().inc("rows", NoLabels);
+        clock.advance(Duration::from_millis(1));
+        {
+            let mut parse = pipeline.debug_metrics().enter_span("parse;lex");
+            parse.debug_metrics().inc("rows", NoLabels);
+            parse.debug_metrics().inc("tokens", NoLabels);
+            clock.advance(Duration::from_millis(3));
+        }
+        clock.advance(Duration::from_millis(2));
+    }
+    debug_metrics.inc("rows", NoLabels);
+    let spans: Vec<Option<String>> = debug_metrics
+        .events_for_key("rows")
+        .into_iter()
+        .map(|e| e.span)
+        .collect();
+    assert_eq!(
+        spans,
+        vec![
+            Some("pipeline".to_string()),
+            Some("pipeline;parse:lex".to_string()),
+            None,
+        ]
+    );
+    let closed: Vec<(String, Duration, Duration)> = debug_metrics
+        .closed_spans()
+        .into_iter()
+        .map(|s| (s.stack, s.start, s.end))
+        .collect();
+    assert_eq!(
+        closed,
+        vec![
+            (
+                "pipeline;parse:lex".to_string(),
+                Duration::from_millis(1),
+                Duration::from_millis(4)
+            ),
+            (
+                "pipeline".to_string(),
+                Duration::ZERO,
+                Duration::from_millis(6)
+            ),
+        ]
+    );
+    assert_eq!(
+        debug_metrics.folded_stacks(StackWeight::Events),
+        BTreeMap::from([
+            ("pipeline".to_string(), 1),
+            ("pipeline;parse:lex".to_string(), 2),
+        ])
+    );
+    let mut folded = Vec::new();
+    write_folded_stacks(&mut folded, &debug_metrics.folded_stacks(StackWeight::Time)).unwrap();
+    assert_eq!(
+        String::from_utf8(folded).unwrap(),
+        "pipeline 3000000\npipeline;parse:lex 3000000\n"
+    );
+}
+
+#[test]
+fn spans_are_kept_per_thread() {
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_clock(ManualClock::new())
+        .safe();
+    let _main = debug_metrics.enter_span("main");
+    let thread_metrics = debug_metrics.clone();
+    std::thread::spawn(move || {
+        let _worker = thread_metrics.enter_span("worker");
+        thread_metrics.inc("jobs", NoLabels);
+    })
+    .join()
+    .unwrap();
+    debug_metrics.inc("jobs", NoLabels);
+    let spans: Vec<Option<String>> = debug_metrics
+        .events_for_key("jobs")
+        .into_iter()
+        .map(|e| e.span)
+        .collect();
+    assert_eq!(
+        spans,
+        vec![Some("worker".to_string()), Some("main".to_string())]
+    );
+    let closed = debug_metrics.closed_spans();
+    assert_eq!(closed.len(), 1);
+    assert_eq!(closed[0].name(), "worker");
+    assert_ne!(closed[0].thread, std::thread::current().id());
+}
+
+#[test]
+fn chrome_trace_output_is_a_timeline_per_thread() {
+    let clock = ManualClock::new();
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::ChromeTrace,
+        ..DebugMetricsConfig::default_on()
+    };
+    let buffer = SharedBuffer::default();
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config)
+        .with_clock(clock.clone())
+        .safe();
+    {
+        let _load = debug_metrics.enter_span("load");
+        clock.advance(Duration::from_nanos(1_500));
+        debug_metrics.set_label("stage", "parse\"d");
+        let timer = debug_metrics.start_timer("read", NoLabels);
+        clock.advance(Duration::from_micros(2));
+        debug_metrics.inc("rows", NoLabels);
+        timer.stop();
+    }
+    let thread_metrics = debug_metrics.clone();
+    std::thread::spawn(move || thread_metrics.sub("rows", 3, NoLabels))
+        .join()
+        .unwrap();
+    let (thread_metrics, thread_clock) = (debug_metrics.clone(), clock.clone());
+    std::thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(move || {
+            let _save = thread_metrics.enter_span("save");
+            thread_clock.advance(Duration::from_micros(1));
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    debug_metrics.flush_report().unwrap();
+    // Only named threads get a name, the spawned thread above is not named
+    let expected = format!(
+        indoc!(
+            r#"
+            [
+            {{"name":"thread_name","ph":"M","pid":{pid},"tid":1,"args":{{"name":"{main}"}}}},
+            {{"name":"stage","ph":"i","ts":1.500,"pid":{pid},"tid":1,"s":"t","args":{{"value":"parse\"d"}}}},
+            {{"name":"rows","ph":"C","ts":3.500,"pid":{pid},"tid":1,"args":{{"value":1}}}},
+            {{"name":"read","ph":"X","ts":1.500,"pid":{pid},"tid":1,"dur":2.000}},
+            {{"name":"rows underflow","ph":"i","ts":3.500,"pid":{pid},"tid":2,"s":"t","args":{{"count":1,"amount":3}}}},
+            {{"name":"rows","ph":"C","ts":3.500,"pid":{pid},"tid":2,"args":{{"value":0}}}},
+            {{"name":"load","ph":"B","ts":0.000,"pid":{pid},"tid":1,"cat":"span"}},
+            {{"name":"load","ph":"E","ts":3.500,"pid":{pid},"tid":1,"cat":"span"}},
+            {{"name":"thread_name","ph":"M","pid":{pid},"tid":3,"args":{{"name":"worker"}}}},
+            {{"name":"save","ph":"B","ts":3.500,"pid":{pid},"tid":3,"cat":"span"}},
+            {{"name":"save","ph":"E","ts":4.500,"pid":{pid},"tid":3,"cat":"span"}}
+            ]
+            "#
+        ),
+        pid = std::process::id(),
+        main = std::thread::current().name().unwrap()
+    );
+    assert_eq!(buffer.output(), expected);
+}
+
+#[test]
+fn chrome_trace_timers_that_overlap_are_complete_events() {
+    let clock = ManualClock::new();
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::ChromeTrace,
+        ..DebugMetricsConfig::default_on()
+    };
+    let buffer = SharedBuffer::default();
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config)
+        .with_clock(clock.clone())
+        .safe();
+    let fetch = debug_metrics.start_timer("fetch", NoLabels);
+    clock.advance(Duration::from_micros(1));
+    let parse = debug_metrics.start_timer("parse", NoLabels);
+    clock.advance(Duration::from_micros(1));
+    fetch.stop();
+    clock.advance(Duration::from_micros(1));
+    parse.stop();
+    debug_metrics.flush_report().unwrap();
+    let expected = format!(
+        indoc!(
+            r#"
+            [
+            {{"name":"thread_name","ph":"M","pid":{pid},"tid":1,"args":{{"name":"{main}"}}}},
+            {{"name":"fetch","ph":"X","ts":0.000,"pid":{pid},"tid":1,"dur":2.000}},
+            {{"name":"parse","ph":"X","ts":1.000,"pid":{pid},"tid":1,"dur":2.000}}
+            ]
+            "#
+        ),
+        pid = std::process::id(),
+        main = std::thread::current().name().unwrap()
+    );
+    assert_eq!(buffer.output(), expected);
+}
+
+#[test]
+fn series_count_every_label_set_separately() {
+    let config = DebugMetricsConfig {
+        series: true,
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    for (route, method) in [("/a", "GET"), ("/b", "GET"), ("/a", "POST"), ("/a", "GET")] {
+        debug_metrics.inc(
+            "requests",
+            [("route", route), ("method", method)].into_iter(),
+        );
+    }
+    debug_metrics.add("requests", 5, NoLabels);
+    debug_metrics.dec("requests", [("route", "/b"), ("method", "GET")].into_iter());
+    let label_set = |labels: &[(&str, &str)]| -> LabelSet {
+        labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    assert_eq!(
+        debug_metrics.series("requests"),
+        BTreeMap::from([
+            (label_set(&[]), 5),
+            (label_set(&[("method", "GET"), ("route", "/a")]), 2),
+            (label_set(&[("method", "GET"), ("route", "/b")]), 0),
+            (label_set(&[("method", "POST"), ("route", "/a")]), 1),
+        ])
+    );
+    assert_eq!(
+        debug_metrics.sum_series_by("requests", &["route"]),
+        BTreeMap::from([
+            (label_set(&[]), 5),
+            (label_set(&[("route", "/a")]), 3),
+            (label_set(&[("route", "/b")]), 0),
+        ])
+    );
+    assert_eq!(
+        debug_metrics.sum_series_by("requests", &[]),
+        BTreeMap::from([(label_set(&[]), 8)])
+    );
+    // Only the series goes below zero, the metric itself is at 8
+    debug_metrics.sub(
+        "requests",
+        1,
+        [("route", "/b"), ("method", "GET")].into_iter(),
+    );
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("requests")),
+        vec![EventType::Underflow {
+            metric: "requests".to_string(),
+            count: 0,
+            amount: 1,
+            labels: label_set(&[("method", "GET"), ("route", "/b")]),
+        }]
+    );
+}
+
+#[test]
+fn metric_underflow_is_recorded_when_its_series_does_not_underflow() {
+    let config = DebugMetricsConfig {
+        series: true,
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    debug_metrics.inc("x", [("k", "b")].into_iter());
+    debug_metrics.set("x", 0, [("k", "a")].into_iter());
+    debug_metrics.sub("x", 1, [("k", "b")].into_iter());
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("x")),
+        vec![EventType::Underflow {
+            metric: "x".to_string(),
+            count: 0,
+            amount: 1,
+            labels: BTreeMap::from([("k".to_string(), "b".to_string())]),
+        }]
+    );
+}
+
+#[test]
+fn series_are_summed_in_the_report() {
+    let config = DebugMetricsConfig {
+        series: true,
+        series_sum_by: &["route"],
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    for (route, method) in [("/a", "GET"), ("/b", "GET"), ("/a", "POST")] {
+        debug_metrics.inc(
+            "requests",
+            [("route", route), ("method", method)].into_iter(),
+        );
+    }
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let expected = indoc!(
+        r#"
+        series requests{route="/a"}: 2
+        series requests{route="/b"}: 1
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn prometheus_output_sanitises_names() {
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::Prometheus,
+        series: true,
+        histogram_buckets: HistogramBuckets::Explicit(&[10, 100]),
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    debug_metrics.inc("http.requests", [("route-name", "/a\"b")].into_iter());
+    debug_metrics.inc("http.requests", [("route-name", "/c")].into_iter());
+    debug_metrics.set_gauge("9lives", -1, NoLabels);
+    for size in [5, 50, 500] {
+        debug_metrics.record("batch size", size, NoLabels);
+    }
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let expected = indoc!(
+        r#"
+        # TYPE _9lives gauge
+        _9lives -1
+        # TYPE http_requests gauge
+        http_requests{route_name="/a\"b"} 1
+        http_requests{route_name="/c"} 1
+        # TYPE batch_size histogram
+        batch_size_bucket{le="10"} 1
+        batch_size_bucket{le="100"} 2
+        batch_size_bucket{le="+Inf"} 3
+        batch_size_sum 555
+        batch_size_count 3
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn prometheus_output_has_one_family_per_name() {
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::Prometheus,
+        histogram_buckets: HistogramBuckets::Explicit(&[10]),
+        ..DebugMetricsConfig::default()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.add("db-reads", 2, NoLabels);
+    debug_metrics.inc("lat", NoLabels);
+    debug_metrics.record("lat", 5, NoLabels);
+    debug_metrics.record("size", 5, NoLabels);
+    debug_metrics.inc("size_count", NoLabels);
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let expected = indoc!(
+        r#"
+        # TYPE db_reads gauge
+        db_reads 2
+        # TYPE lat gauge
+        lat 1
+        # TYPE size_count gauge
+        size_count 1
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn textfiles_are_rewritten_while_running() {
+    let path = std::env::temp_dir().join(format!(
+        "debug-metrics-{}-textfile.prom",
+        std::process::id()
+    ));
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default()).safe();
+    debug_metrics.inc("jobs", NoLabels);
+    debug_metrics.write_textfile(&path).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# TYPE jobs gauge\njobs 1\n"
+    );
+    let writer = debug_metrics.spawn_textfile_writer(&path, Duration::from_secs(3600));
+    debug_metrics.inc("jobs", NoLabels);
+    // Stopping the writer writes the file one last time
+    drop(writer);
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# TYPE jobs gauge\njobs 2\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn dot_output_is_a_graph_of_causes_and_dependencies() {
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::Dot,
+        ..DebugMetricsConfig::default_on()
+    };
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+    debug_metrics.add_recording_rule("requests", &["^errors$"]);
+    debug_metrics.inc("errors", NoLabels);
+    for route in ["/a", "/b", "/a\"quoted"] {
+        debug_metrics.inc("requests", [("route", route)].into_iter());
+    }
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    let expected = indoc!(
+        r#"
+        digraph debug_metrics {
+            "errors" [shape=ellipse];
+            "requests" [shape=ellipse];
+            "route" [shape=box];
+            "errors" -> "requests" [label="3", weight=3, style=dashed];
+            "requests" -> "route" [label="3", weight=3];
+        }
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn sharded_collector_replays_changes_in_order() {
+    let clock = ManualClock::new();
+    let debug_metrics = DebugMetricsSharded::with_shards(
+        DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).with_clock(clock.clone()),
+        4,
+    );
+    debug_metrics.set_label("stage", "start");
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let debug_metrics = debug_metrics.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    debug_metrics.inc("jobs", NoLabels);
+                }
+                std::thread::current().id()
+            })
+        })
+        .collect();
+    let threads: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    let events = debug_metrics.events_for_key("jobs");
+    let counts: Vec<i64> = events
+        .iter()
+        .map(|e| match e.event_type {
+            EventType::MetricChange { count, .. } => count,
+            _ => panic!("unexpected event {e:?}"),
+        })
+        .collect();
+    assert_eq!(counts, (1..=8000).collect::<Vec<_>>());
+    for thread in threads {
+        assert_eq!(events.iter().filter(|e| e.thread == thread).count(), 1000);
+    }
+}
+
+#[test]
+fn sharded_collector_replays_full_shards_in_the_background() {
+    let buffer = SharedBuffer::default();
+    let debug_metrics = DebugMetricsSharded::with_shards(
+        DebugMetrics::new(buffer.clone(), DebugMetricsConfig::default_on()),
+        1,
+    );
+    // Enough to fill the shard, and to reach the limit if the background thread falls behind
+    for _ in 0..20_000 {
+        debug_metrics.inc("jobs", NoLabels);
+    }
+    let counts: Vec<i64> = debug_metrics
+        .events_for_key("jobs")
+        .iter()
+        .map(|e| match e.event_type {
+            EventType::MetricChange { count, .. } => count,
+            _ => panic!("unexpected event {e:?}"),
+        })
+        .collect();
+    assert_eq!(counts, (1..=20_000).collect::<Vec<_>>());
+    // The report is written by the time the last clone is dropped, even while replaying
+    let line = line!() + 1;
+    debug_metrics.inc("jobs", NoLabels);
+    drop(debug_metrics);
+    let last = format!("{}:{line}:19: jobs: 20001 :: {{}}\n", file!());
+    assert!(buffer.output().contains(&last), "{}", buffer.output().len());
+}
+
+#[test]
+fn sharded_collector_keeps_when_and_where_changes_happened() {
+    let clock = ManualClock::new();
+    let buffer = SharedBuffer::default();
+    let debug_metrics = DebugMetrics::new(buffer.clone(), DebugMetricsConfig::default_on())
+        .with_clock(clock.clone())
+        .sharded();
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    clock.advance(Duration::from_millis(1));
+    let thread_metrics = debug_metrics.clone();
+    let thread = std::thread::spawn(move || {
+        thread_metrics.inc("example", NoLabels);
+        std::thread::current().id()
+    })
+    .join()
+    .unwrap();
+    clock.advance(Duration::from_millis(1));
+    let events = debug_metrics.events_for_key("example");
+    assert_eq!(
+        events
+            .iter()
+            .map(|e| (e.sequence, e.elapsed, e.location.line()))
+            .collect::<Vec<_>>(),
+        vec![
+            (0, Duration::ZERO, line),
+            (1, Duration::from_millis(1), line + 4),
+        ]
+    );
+    assert_eq!(events[0].thread, std::thread::current().id());
+    assert_eq!(events[1].thread, thread);
+    let last = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    // Changes that were never queried are replayed before the report
+    drop(debug_metrics);
+    let (file, spawned) = (file!(), line + 4);
+    let expected = formatdoc!(
+        r#"
+        [#0 +0ns] {file}:{line}:19: example: 1 :: {{}}
+        [#1 +1ms] {file}:{spawned}:24: example: 2 :: {{}}
+        [#2 +2ms] {file}:{last}:19: example: 3 :: {{}}
+        call site {file}:{line}:19: {{"example": 1}}
+        call site {file}:{spawned}:24: {{"example": 1}}
+        call site {file}:{last}:19: {{"example": 1}}
+    "#
+    );
+    assert_eq!(buffer.output(), expected);
+}
+
+#[test]
+fn thread_labels_do_not_overwrite_each_other() {
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on()).safe();
+    debug_metrics.set_label("job", "import");
+    let handles: Vec<_> = ["parse", "load"]
+        .into_iter()
+        .map(|stage| {
+            let debug_metrics = debug_metrics.clone();
+            std::thread::Builder::new()
+                .name(format!("worker-{stage}"))
+                .spawn(move || {
+                    debug_metrics.set_thread_label("stage", stage);
+                    debug_metrics.inc(format!("{stage}_rows"), NoLabels);
+                })
+                .unwrap()
+        })
+        .collect();
+    handles.into_iter().for_each(|h| h.join().unwrap());
+    for stage in ["parse", "load"] {
+        let events = debug_metrics.events_for_key(format!("{stage}_rows"));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].thread_name.as_deref(),
+            Some(format!("worker-{stage}").as_str())
+        );
+        let EventType::MetricChange { labels, .. } = &events[0].event_type else {
+            panic!("unexpected event {:?}", events[0]);
+        };
+        assert_eq!(
+            labels,
+            &BTreeMap::from([
+                ("job".to_string(), "import".to_string()),
+                ("stage".to_string(), stage.to_string()),
+            ])
+        );
+        let label_events = debug_metrics.events_for_key("stage");
+        assert!(label_events.iter().any(|e| matches!(
+            &e.event_type,
+            EventType::LabelChange { value, .. } if value == stage
+        )));
+    }
+    // Thread labels are not global
+    debug_metrics.inc("main_rows", NoLabels);
+    let events = debug_metrics.events_for_key("main_rows");
+    let EventType::MetricChange { labels, .. } = &events[0].event_type else {
+        panic!("unexpected event {:?}", events[0]);
+    };
+    assert_eq!(
+        labels,
+        &BTreeMap::from([("job".to_string(), "import".to_string())])
+    );
+}
+
+#[test]
+fn rules_added_later_capture_thread_labels() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.set_thread_label("request_id", "r-1");
+    debug_metrics.set_thread_label("tenant", "acme");
+    debug_metrics.add_recording_rule("query", &["^request_id$"]);
+    debug_metrics.add_pattern_recording_rule(KeyPattern::Glob("db.*"), &["^request_id$"]);
+    // Extending a pattern rule rescans the keys as well
+    debug_metrics.add_pattern_recording_rule(KeyPattern::Glob("db.*"), &["^tenant$"]);
+    debug_metrics.add_rule(
+        RecordingRuleBuilder::for_key("retries")
+            .with_captures(&["^tenant$"])
+            .with_condition(Condition::Compare(Comparison::Greater, 0)),
+    );
+    debug_metrics.inc("query", NoLabels);
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.inc("retries", NoLabels);
+    let labels_of = |key: &str| {
+        let events = debug_metrics.events_for_key(key);
+        assert_eq!(events.len(), 1, "{events:?}");
+        let EventType::MetricChange { labels, .. } = &events[0].event_type else {
+            panic!("unexpected event {:?}", events[0]);
+        };
+        labels.clone()
+    };
+    let label = |k: &str, v: &str| (k.to_string(), v.to_string());
+    assert_eq!(
+        labels_of("query"),
+        BTreeMap::from([label("request_id", "r-1")])
+    );
+    assert_eq!(
+        labels_of("db.reads"),
+        BTreeMap::from([label("request_id", "r-1"), label("tenant", "acme")])
+    );
+    assert_eq!(
+        labels_of("retries"),
+        BTreeMap::from([label("tenant", "acme")])
+    );
+}
+
+#[test]
+fn thread_labels_of_exited_threads_are_forgotten() {
+    fn thread_label_lines(dump: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(dump)
+            .lines()
+            .filter(|line| line.starts_with("ThreadId"))
+            .map(str::to_string)
+            .collect()
+    }
+    let debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    // Joined rather than scoped, as a scope can end before the thread locals of its threads are
+    // dropped, which is when the thread is known to have exited
+    let mut debug_metrics = std::thread::spawn(move || {
+        let mut debug_metrics = debug_metrics;
+        debug_metrics.set_thread_label("stage", "worker");
+        debug_metrics
+    })
+    .join()
+    .unwrap();
+    debug_metrics.set_thread_label("stage", "main");
+    debug_metrics.dump(DumpTarget::Writer).unwrap();
+    let dump = debug_metrics.finish().unwrap();
+    assert_eq!(
+        thread_label_lines(&dump),
+        vec![format!(
+            "{:?} labels: {{\"stage\": \"main\"}}",
+            std::thread::current().id()
+        )]
+    );
+
+    // The labels of a sharded thread are only forgotten after the ops it buffered are replayed
+    let debug_metrics = DebugMetricsSharded::with_shards(
+        DebugMetrics::new(Vec::new(), DebugMetricsConfig::default()),
+        2,
+    );
+    debug_metrics.add_recording_rule("rows", &["^stage$"]);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            debug_metrics.set_thread_label("stage", "worker");
+            debug_metrics.inc("rows", NoLabels);
+        });
+    });
+    let events = debug_metrics.events_for_key("rows");
+    let EventType::MetricChange { labels, .. } = &events[0].event_type else {
+        panic!("unexpected event {:?}", events[0]);
+    };
+    assert_eq!(
+        labels,
+        &BTreeMap::from([("stage".to_string(), "worker".to_string())])
+    );
+}
+
+#[test]
+fn events_can_be_grouped_by_thread() {
+    let config = DebugMetricsConfig {
+        group_by_thread: true,
+        ..DebugMetricsConfig::default_on()
+    };
+    let buffer = SharedBuffer::default();
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config)
+        .with_clock(ManualClock::new())
+        .safe();
+    let line = line!() + 1;
+    debug_metrics.inc("example", NoLabels);
+    let thread_metrics = debug_metrics.clone();
+    std::thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(move || thread_metrics.inc("example", NoLabels))
+        .unwrap()
+        .join()
+        .unwrap();
+    let thread_metrics = debug_metrics.clone();
+    std::thread::spawn(move || thread_metrics.set_thread_label("stage", "unnamed"))
+        .join()
+        .unwrap();
+    debug_metrics.inc("example", NoLabels);
+    let main = std::thread::current();
+    let main = main.name().unwrap();
+    drop(debug_metrics);
+    let output = buffer.output();
+    let lines: Vec<_> = output.lines().collect();
+    let file = file!();
+    let (worker, unnamed, last) = (line + 4, line + 9, line + 12);
+    assert_eq!(lines.len(), 8, "{output}");
+    assert_eq!(
+        lines[0],
+        format!("[#0 +0ns] <{main}> {file}:{line}:19: example: 1 :: {{}}")
+    );
+    assert_eq!(
+        lines[1],
+        format!("[#3 +0ns] <{main}> {file}:{last}:19: example: 3 :: {{}}")
+    );
+    assert_eq!(
+        lines[2],
+        format!("[#1 +0ns] <worker> {file}:{worker}:39: example: 2 :: {{}}")
+    );
+    assert!(lines[3].starts_with("[#2 +0ns] <ThreadId("), "{output}");
+    assert!(lines[3].ends_with(&format!(
+        "> {file}:{unnamed}:47: stage: unnamed :: {{\"stage\": \"unnamed\"}}"
+    )));
+    assert_eq!(
+        lines[4],
+        format!("call site {file}:{line}:19: {{\"example\": 1}}")
+    );
+    assert_eq!(
+        lines[7],
+        format!("call site {file}:{last}:19: {{\"example\": 1}}")
+    );
+}
+
+#[test]
+fn scoped_labels_restore_the_previous_value() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default_on())
+        .with_clock(ManualClock::new());
+    let line = line!() + 1;
+    debug_metrics.set_label("phase", "startup");
+    {
+        let mut phase = debug_metrics.scoped_label("phase", "compaction");
+        let mut table = phase.debug_metrics().scoped_label("table", "users");
+        table.debug_metrics().inc("rows", NoLabels);
+    }
+    debug_metrics.inc("rows", NoLabels);
+    let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+    // Restoring a label is located where its scope was opened
+    let at = |offset: u32, column: u32| format!("{}:{}:{column}", file!(), line + offset);
+    let (startup, phase, table) = (at(0, 19), at(2, 39), at(3, 47));
+    let (scoped_rows, rows) = (at(4, 31), at(6, 19));
+    let expected = formatdoc!(
+        r#"
+        [#0 +0ns] {startup}: phase: startup :: {{"phase": "startup"}}
+        [#1 +0ns] {phase}: phase: compaction :: {{"phase": "compaction"}}
+        [#2 +0ns] {table}: table: users :: {{"phase": "compaction", "table": "users"}}
+        [#3 +0ns] {scoped_rows}: rows: 1 :: {{"phase": "compaction", "table": "users"}}
+        [#4 +0ns] {table}: table: removed users :: {{"phase": "compaction"}}
+        [#5 +0ns] {phase}: phase: startup :: {{"phase": "startup"}}
+        [#6 +0ns] {rows}: rows: 2 :: {{"phase": "startup"}}
+        call site {startup}: {{"phase": 1}}
+        call site {phase}: {{"phase": 2}}
+        call site {table}: {{"table": 2}}
+        call site {scoped_rows}: {{"rows": 1}}
+        call site {rows}: {{"rows": 1}}
+    "#
+    );
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn removed_labels_are_recorded_as_events() {
+    let buffer = SharedBuffer::default();
+    let config = DebugMetricsConfig {
+        output_format: OutputFormat::JsonLines,
+        ..DebugMetricsConfig::default()
+    };
+    let debug_metrics = DebugMetrics::new(buffer.clone(), config).safe();
+    debug_metrics.add_recording_rule("phase", &["rows"]);
+    debug_metrics.add_drop_hook("phase");
+    debug_metrics.inc("rows", NoLabels);
+    {
+        let _phase = debug_metrics.scoped_label("phase", "compaction");
+        debug_metrics.inc("rows", NoLabels);
+    }
+    // Removing a label that is not set records nothing
+    debug_metrics.remove_label("phase");
+    let events = event_types(debug_metrics.events_for_key("phase"));
+    assert_eq!(
+        events,
+        vec![
+            EventType::LabelChange {
+                label: "phase".to_string(),
+                value: "compaction".to_string(),
+                dependencies: BTreeMap::from([("rows".to_string(), 1)]),
+                labels: Default::default(),
+            },
+            EventType::LabelRemoved {
+                label: "phase".to_string(),
+                value: "compaction".to_string(),
+                dependencies: BTreeMap::from([("rows".to_string(), 2)]),
+                labels: Default::default(),
+            },
+        ]
+    );
+    drop(debug_metrics);
+    let output = buffer.output();
+    let removed = output.lines().nth(1).unwrap();
+    assert!(
+        removed.starts_with("{\"kind\":\"label_removed\",\"sequence\":1,"),
+        "{output}"
+    );
+    assert!(removed.ends_with(
+        "\"label\":\"phase\",\"value\":\"compaction\",\"dependencies\":{\"rows\":2},\"labels\":{}}"
+    ));
+}
+
+#[test]
+fn recording_rules_can_be_triggered_by_patterns() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.set_label("request_id", "r-1");
+    debug_metrics.add_pattern_recording_rule(KeyPattern::Glob("db.*"), &["^request_id$"]);
+    debug_metrics.add_pattern_recording_rule(KeyPattern::Regex("^db\\.writes$"), &["^db\\.reads$"]);
+    debug_metrics.add_recording_rule("db.writes", &["^cache\\."]);
+    debug_metrics.inc("cache.hits", NoLabels);
+    // A key seen before the rule was added triggers it as well
+    debug_metrics.inc("db.reads", NoLabels);
+    debug_metrics.inc("db.writes", NoLabels);
+    // Globs match whole keys
+    debug_metrics.inc("replica.db.reads", NoLabels);
+    debug_metrics.record("db.latency", 5, NoLabels);
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("db.reads")),
+        vec![EventType::MetricChange {
+            metric: "db.reads".to_string(),
+            count: 2,
+            dependencies: Default::default(),
+            labels: BTreeMap::from([("request_id".to_string(), "r-1".to_string())]),
+        }]
+    );
+    // Both pattern rules and the exact rule are triggered
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("db.writes")),
+        vec![EventType::MetricChange {
+            metric: "db.writes".to_string(),
+            count: 1,
+            dependencies: BTreeMap::from([
+                ("cache.hits".to_string(), 1),
+                ("db.reads".to_string(), 2),
+            ]),
+            labels: BTreeMap::from([("request_id".to_string(), "r-1".to_string())]),
+        }]
+    );
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("replica.db.reads")),
+        vec![]
+    );
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("db.latency")),
+        vec![EventType::Sample {
+            metric: "db.latency".to_string(),
+            value: 5,
+            dependencies: Default::default(),
+            labels: BTreeMap::from([("request_id".to_string(), "r-1".to_string())]),
+        }]
+    );
+    let err = debug_metrics
+        .try_add_pattern_recording_rule(KeyPattern::Regex("db.(unclosed"), &[])
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            DebugMetricsError::InvalidTrigger { pattern, .. } if *pattern == "db.(unclosed"
+        ),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn conditional_rules_only_record_events_when_the_condition_holds() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.set_label("request_id", "r-1");
+    debug_metrics.add_rule(
+        RecordingRuleBuilder::for_key("retries")
+            .with_captures(&["^request_id$"])
+            .with_condition(Condition::Compare(Comparison::Greater, 3)),
+    );
+    debug_metrics.add_rule(
+        RecordingRuleBuilder::for_pattern(KeyPattern::Glob("*.status"))
+            .with_condition(Condition::LabelMatches("^error")),
+    );
+    debug_metrics
+        .add_rule(RecordingRuleBuilder::for_key("queue_depth").with_condition(Condition::Changed));
+    for _ in 0..5 {
+        debug_metrics.inc("retries", NoLabels);
+    }
+    for status in ["ok", "error: timeout", "ok"] {
+        debug_metrics.set_label("db.status", status);
+    }
+    for depth in [1, 1, 2, 2, 1] {
+        debug_metrics.set("queue_depth", depth, NoLabels);
+    }
+    let counts = |events: Vec<Event>| -> Vec<i64> {
+        event_types(events)
+            .into_iter()
+            .map(|e| match e {
+                EventType::MetricChange { count, .. } => count,
+                e => panic!("unexpected event {e:?}"),
+            })
+            .collect()
+    };
+    let retries = debug_metrics.events_for_key("retries");
+    assert_eq!(counts(retries.clone()), vec![4, 5]);
+    assert_eq!(
+        event_types(retries)[0],
+        EventType::MetricChange {
+            metric: "retries".to_string(),
+            count: 4,
+            dependencies: Default::default(),
+            labels: BTreeMap::from([("request_id".to_string(), "r-1".to_string())]),
+        }
+    );
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("db.status")),
+        vec![EventType::LabelChange {
+            label: "db.status".to_string(),
+            value: "error: timeout".to_string(),
+            dependencies: Default::default(),
+            labels: Default::default(),
+        }]
+    );
+    assert_eq!(
+        counts(debug_metrics.events_for_key("queue_depth")),
+        vec![1, 2, 1]
+    );
+}
+
+#[test]
+fn conditions_do_not_change_unconditional_rules() {
+    let mut debug_metrics = DebugMetrics::new(Vec::new(), DebugMetricsConfig::default());
+    debug_metrics.add_recording_rule("latency", &[]);
+    debug_metrics.add_rule(
+        RecordingRuleBuilder::for_key("latency")
+            .with_captures(&["^slow$"])
+            .with_condition(Condition::Compare(Comparison::GreaterOrEqual, 100)),
+    );
+    debug_metrics.inc("slow", NoLabels);
+    debug_metrics.record("latency", 10, NoLabels);
+    debug_metrics.record("latency", 200, NoLabels);
+    // Only the conditional rule captures `slow`, and only when it holds
+    assert_eq!(
+        event_types(debug_metrics.events_for_key("latency")),
+        vec![
+            EventType::Sample {
+                metric: "latency".to_string(),
+                value: 10,
+                dependencies: Default::default(),
+                labels: Default::default(),
+            },
+            EventType::Sample {
+                metric: "latency".to_string(),
+                value: 200,
+                dependencies: BTreeMap::from([("slow".to_string(), 1)]),
+                labels: Default::default(),
+            },
+        ]
+    );
+    let err = debug_metrics
+        .try_add_rule(
+            RecordingRuleBuilder::for_key("status")
+                .with_condition(Condition::LabelMatches("(unclosed")),
+        )
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            DebugMetricsError::InvalidRecordingRule { metric, pattern, .. }
+                if metric == "status" && *pattern == "(unclosed"
+        ),
+        "{err:?}"
+    );
+}
@@ -0,0 +1,45 @@
+//! Tests for whether metrics are collected, run with each combination of features and profiles,
+//! see `make test-features`.
+use crate::config::DebugMetricsConfig;
+use crate::debug_metrics::{DebugMetricsTrait, DefaultExt};
+use crate::label_iter::NoLabels;
+use crate::DebugMetrics;
+
+#[test]
+fn collection_is_compiled_in_according_to_features() {
+    let expected = !cfg!(feature = "force-disable")
+        && (cfg!(feature = "force-enable") || cfg!(debug_assertions));
+    assert_eq!(cfg!(debug_metrics_enabled), expected);
+}
+
+#[test]
+fn metrics_are_collected_when_compiled_in_and_enabled() {
+    for enabled in [true, false] {
+        let config = DebugMetricsConfig {
+            enabled,
+            ..DebugMetricsConfig::default_on()
+        };
+        let mut debug_metrics = DebugMetrics::new(Vec::new(), config);
+        debug_metrics.set_label("stage", "one");
+        debug_metrics.inc("example", NoLabels);
+        debug_metrics.set("example", 5, NoLabels);
+        let events = debug_metrics.events_for_key("example");
+        let output = String::from_utf8(debug_metrics.finish().unwrap()).unwrap();
+        if cfg!(debug_metrics_enabled) && enabled {
+            assert_eq!(events.len(), 2, "enabled: {enabled}");
+            // Two events and the label change, plus a call site line for each
+            assert_eq!(output.lines().count(), 6, "enabled: {enabled}");
+        } else {
+            assert_eq!(events, vec![], "enabled: {enabled}");
+            assert_eq!(output, "", "enabled: {enabled}");
+        }
+    }
+}
+
+#[cfg(not(debug_metrics_enabled))]
+#[test]
+fn macros_compile_to_nothing() {
+    crate::dm_inc!(unreachable!("arguments are not evaluated"));
+    crate::dm_set!("example", unreachable!("arguments are not evaluated"));
+    crate::dm_label!("stage", unreachable!("arguments are not evaluated"));
+}
@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::ThreadId;
+
+/// Threads that exited since a collector last forgot their thread labels.
+pub(crate) type ExitedThreads = Arc<Mutex<Vec<ThreadId>>>;
+
+thread_local! {
+    static ON_EXIT: RefCell<ExitNotifier> = RefCell::new(ExitNotifier {
+        thread: std::thread::current().id(),
+        targets: Vec::new(),
+    });
+}
+
+/// Pushes its thread to each target when the thread exits and its thread locals are dropped.
+struct ExitNotifier {
+    thread: ThreadId,
+    /// Weak, so that a dropped collector is not kept alive by the threads that used it
+    targets: Vec<Weak<Mutex<Vec<ThreadId>>>>,
+}
+
+impl Drop for ExitNotifier {
+    fn drop(&mut self) {
+        for target in self.targets.iter().filter_map(Weak::upgrade) {
+            target
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(self.thread);
+        }
+    }
+}
+
+/// Push the current thread to `exited` when it exits.
+pub(crate) fn notify_on_exit(exited: &ExitedThreads) {
+    // Not notified when the thread locals of this thread are already being dropped, in which case
+    // the thread is exiting anyway
+    let _ = ON_EXIT.try_with(|notifier| {
+        let mut notifier = notifier.borrow_mut();
+        notifier.targets.retain(|target| target.strong_count() > 0);
+        if !notifier
+            .targets
+            .iter()
+            .any(|target| std::ptr::eq(target.as_ptr(), Arc::as_ptr(exited)))
+        {
+            notifier.targets.push(Arc::downgrade(exited));
+        }
+    });
+}
@@ -0,0 +1,63 @@
+use crate::debug_metrics::DebugMetricsTrait;
+use std::panic::Location;
+use std::time::Duration;
+
+/// Records how long a scope took when dropped, see [DebugMetricsTrait::start_timer].
+///
+/// The time is measured by the clock of the collector. Dropping the timer while unwinding from a
+/// panic records the time as panicked.
+pub struct Timer<'a, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    pub(crate) debug_metrics: &'a mut DM,
+    pub(crate) key: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) start: Duration,
+    pub(crate) location: &'static Location<'static>,
+    pub(crate) finished: bool,
+}
+
+impl<DM> Timer<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    /// The collector, for recording metrics while the timer is running.
+    pub fn debug_metrics(&mut self) -> &mut DM {
+        self.debug_metrics
+    }
+
+    /// Record the time now instead of on drop, and return it.
+    pub fn stop(mut self) -> Duration {
+        self.finish()
+    }
+
+    /// Drop the timer without recording anything.
+    pub fn discard(mut self) {
+        self.finished = true;
+    }
+
+    fn finish(&mut self) -> Duration {
+        self.finished = true;
+        let duration = self.debug_metrics.elapsed().saturating_sub(self.start);
+        self.debug_metrics.record_duration(
+            std::mem::take(&mut self.key),
+            duration,
+            std::thread::panicking(),
+            std::mem::take(&mut self.labels).into_iter(),
+            self.location,
+        );
+        duration
+    }
+}
+
+impl<DM> Drop for Timer<'_, DM>
+where
+    DM: DebugMetricsTrait + ?Sized,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish();
+        }
+    }
+}
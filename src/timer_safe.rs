@@ -0,0 +1,58 @@
+use crate::debug_metrics_safe::DebugMetricsSafeTrait;
+use std::panic::Location;
+use std::time::Duration;
+
+/// Records how long a scope took when dropped, see [DebugMetricsSafeTrait::start_timer].
+///
+/// Holds a clone of the collector, so it can be moved to other threads and the collector can still
+/// be used while it runs.
+pub struct TimerSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    pub(crate) debug_metrics: DM,
+    pub(crate) key: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) start: Duration,
+    pub(crate) location: &'static Location<'static>,
+    pub(crate) finished: bool,
+}
+
+impl<DM> TimerSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    /// Record the time now instead of on drop, and return it.
+    pub fn stop(mut self) -> Duration {
+        self.finish()
+    }
+
+    /// Drop the timer without recording anything.
+    pub fn discard(mut self) {
+        self.finished = true;
+    }
+
+    fn finish(&mut self) -> Duration {
+        self.finished = true;
+        let duration = self.debug_metrics.elapsed().saturating_sub(self.start);
+        self.debug_metrics.record_duration(
+            std::mem::take(&mut self.key),
+            duration,
+            std::thread::panicking(),
+            std::mem::take(&mut self.labels).into_iter(),
+            self.location,
+        );
+        duration
+    }
+}
+
+impl<DM> Drop for TimerSafe<DM>
+where
+    DM: DebugMetricsSafeTrait,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finish();
+        }
+    }
+}